@@ -0,0 +1,5 @@
+ALTER TABLE records ADD COLUMN IF NOT EXISTS lat real default null;
+ALTER TABLE records ADD COLUMN IF NOT EXISTS lon real default null;
+ALTER TABLE records ADD COLUMN IF NOT EXISTS heading real default null;
+ALTER TABLE records ADD COLUMN IF NOT EXISTS track varchar default null;
+ALTER TABLE records ADD COLUMN IF NOT EXISTS track_change varchar default null;
@@ -0,0 +1,22 @@
+create table if not exists record_cars (
+  record_id uuid not null,
+  position smallint not null,
+  car_number int not null,
+  primary key (record_id, position)
+);
+create index if not exists record_cars_car_number_idx on record_cars(car_number);
+
+-- Backfill from the comma joined consists, skipping placeholders like TBD the same way
+-- septa::consist::parse_consist does.
+insert into record_cars (record_id, position, car_number)
+select
+  id,
+  row_number() over (partition by id order by ord),
+  car::int
+from (
+  select records.id, trim(c.car) as car, c.ord
+  from records
+  cross join lateral unnest(string_to_array(records.consist, ',')) with ordinality as c(car, ord)
+) cars
+where car ~ '^[0-9]{1,9}$'
+on conflict do nothing;
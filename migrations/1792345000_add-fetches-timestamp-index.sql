@@ -0,0 +1 @@
+create index if not exists fetches_timestamp_idx on fetches(timestamp);
@@ -0,0 +1,17 @@
+create table if not exists stations (
+  id varchar primary key,
+  name varchar not null,
+  aliases varchar[] not null default '{}',
+  lines varchar[] not null default '{}',
+  lat real not null,
+  lon real not null
+);
+
+-- Station ids for the raw names, null where the name isn't in the catalogue. Filled in during
+-- ingest, and for older records by `septa normalise-stations`.
+alter table records
+  add column if not exists currentstop_id varchar,
+  add column if not exists nextstop_id varchar,
+  add column if not exists source_id varchar,
+  add column if not exists dest_id varchar;
+create index if not exists records_currentstop_id_idx on records(currentstop_id);
@@ -0,0 +1,50 @@
+-- The most recently imported GTFS static feed. Importing a feed replaces all of these.
+create table if not exists gtfs_stops (
+  stop_id varchar primary key,
+  stop_name varchar not null,
+  lat real,
+  lon real,
+  -- From the stations catalogue, null when the name doesn't match a station.
+  station_id varchar
+);
+
+create table if not exists gtfs_trips (
+  trip_id varchar primary key,
+  route_id varchar not null,
+  service_id varchar not null,
+  trip_short_name varchar,
+  trip_headsign varchar,
+  direction_id smallint
+);
+create index if not exists gtfs_trips_trip_short_name_idx on gtfs_trips(trip_short_name);
+
+-- Times are seconds after noon minus 12 hours on the service date, so they can run past 24h.
+create table if not exists gtfs_stop_times (
+  trip_id varchar not null,
+  stop_sequence int not null,
+  stop_id varchar not null,
+  arrival_secs int,
+  departure_secs int,
+  primary key (trip_id, stop_sequence)
+);
+
+create table if not exists gtfs_calendar (
+  service_id varchar primary key,
+  monday boolean not null,
+  tuesday boolean not null,
+  wednesday boolean not null,
+  thursday boolean not null,
+  friday boolean not null,
+  saturday boolean not null,
+  sunday boolean not null,
+  start_date date not null,
+  end_date date not null
+);
+
+create table if not exists gtfs_calendar_dates (
+  service_id varchar not null,
+  date date not null,
+  -- 1 adds service on the date, 2 removes it.
+  exception_type smallint not null,
+  primary key (service_id, date)
+);
@@ -0,0 +1,2 @@
+create index if not exists records_received_at_idx on records(received_at);
+create index if not exists records_trainno_received_at_idx on records(trainno, received_at desc);
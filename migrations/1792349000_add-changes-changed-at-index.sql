@@ -0,0 +1 @@
+create index if not exists changes_changed_at_id_idx on changes(changed_at, id);
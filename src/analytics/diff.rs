@@ -0,0 +1,158 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{config::SeptaConfig, db::tracking::Changed, septa::train_view::TrainView};
+
+/// A train in both snapshots whose record differs between them.
+#[derive(Debug, Serialize)]
+pub struct ChangedTrain {
+    /// The train as of `to`.
+    pub train: TrainView,
+    pub changes: Vec<Changed>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct LineDiff {
+    pub added: Vec<TrainView>,
+    /// Trains as of `from`.
+    pub removed: Vec<TrainView>,
+    pub changed: Vec<ChangedTrain>,
+    pub unchanged: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NetworkDiff {
+    #[serde(serialize_with = "crate::serde_utils::serialize_date_time")]
+    pub from: DateTime<Utc>,
+    #[serde(serialize_with = "crate::serde_utils::serialize_date_time")]
+    pub to: DateTime<Utc>,
+    /// Removed trains are listed under their line as of `from`, the rest under their line as of
+    /// `to`, so a train that moved lines shows up once, under its new line.
+    pub lines: BTreeMap<String, LineDiff>,
+}
+
+/// Compares two snapshots of the network, by train number.
+pub fn diff(from: Vec<TrainView>, to: Vec<TrainView>) -> BTreeMap<String, LineDiff> {
+    let mut lines: BTreeMap<String, LineDiff> = BTreeMap::new();
+    let mut before: HashMap<String, TrainView> = from
+        .into_iter()
+        .map(|train| (train.trainno.clone(), train))
+        .collect();
+    for train in to {
+        let line = lines.entry(train.line.clone()).or_default();
+        match before.remove(&train.trainno) {
+            None => line.added.push(train),
+            Some(prev) => match train.get_changes(&prev) {
+                Some(changes) => line.changed.push(ChangedTrain { train, changes }),
+                None => line.unchanged += 1,
+            },
+        }
+    }
+    for (_, train) in before {
+        lines
+            .entry(train.line.clone())
+            .or_default()
+            .removed
+            .push(train);
+    }
+    for line in lines.values_mut() {
+        line.added.sort_by(|a, b| a.trainno.cmp(&b.trainno));
+        line.removed.sort_by(|a, b| a.trainno.cmp(&b.trainno));
+        line.changed
+            .sort_by(|a, b| a.train.trainno.cmp(&b.train.trainno));
+    }
+    lines
+}
+
+/// Diffs the snapshots `/api/snapshot` would give at `from` and `to`. Unless `all` is set, each
+/// only has the trains seen that service day, so trains from an earlier day don't count as
+/// running.
+pub async fn diff_snapshots(
+    pool: PgPool,
+    config: &SeptaConfig,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    all: bool,
+) -> anyhow::Result<NetworkDiff> {
+    let since =
+        |at: DateTime<Utc>| (!all).then(|| config.service_date_bounds(config.service_date(at)).0);
+    let before = TrainView::get_snapshot(pool.clone(), Some(from), since(from)).await?;
+    let after = TrainView::get_snapshot(pool, Some(to), since(to)).await?;
+    Ok(NetworkDiff {
+        from,
+        to,
+        lines: diff(before, after),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn train(trainno: &str, line: &str, late: i32) -> TrainView {
+        serde_json::from_value(serde_json::json!({
+            "trainno": trainno,
+            "service": "LOCAL",
+            "dest": "Thorndale",
+            "currentstop": "Ardmore",
+            "nextstop": "Haverford",
+            "line": line,
+            "consist": "815,816",
+            "late": late,
+            "SOURCE": "30th Street Station",
+        }))
+        .unwrap()
+    }
+
+    fn trainnos(trains: &[TrainView]) -> Vec<&str> {
+        trains.iter().map(|train| train.trainno.as_str()).collect()
+    }
+
+    #[test]
+    fn trains_added_removed_and_changed() {
+        let lines = diff(
+            vec![
+                train("1001", "Paoli/Thorndale", 0),
+                train("1002", "Paoli/Thorndale", 0),
+                train("1003", "Paoli/Thorndale", 1),
+            ],
+            vec![
+                train("1002", "Paoli/Thorndale", 4),
+                train("1003", "Paoli/Thorndale", 1),
+                train("2001", "Trenton", 0),
+            ],
+        );
+        assert_eq!(
+            lines.keys().collect::<Vec<_>>(),
+            vec!["Paoli/Thorndale", "Trenton"]
+        );
+
+        let paoli = &lines["Paoli/Thorndale"];
+        assert!(paoli.added.is_empty());
+        assert_eq!(trainnos(&paoli.removed), vec!["1001"]);
+        assert_eq!(paoli.unchanged, 1);
+        let [changed] = paoli.changed.as_slice() else {
+            panic!("expected one changed train, got {:?}", paoli.changed);
+        };
+        assert_eq!(changed.train.trainno, "1002");
+        let fields: Vec<(&str, String, String)> = changed
+            .changes
+            .iter()
+            .map(|change| {
+                (
+                    change.field.as_str(),
+                    change.old_value.to_sql_fields().1,
+                    change.new_value.to_sql_fields().1,
+                )
+            })
+            .collect();
+        assert_eq!(fields, vec![("late", "0".to_string(), "4".to_string())]);
+
+        let trenton = &lines["Trenton"];
+        assert_eq!(trainnos(&trenton.added), vec!["2001"]);
+        assert!(trenton.removed.is_empty() && trenton.changed.is_empty());
+        assert_eq!(trenton.unchanged, 0);
+    }
+}
@@ -0,0 +1,113 @@
+use chrono::NaiveDate;
+use futures::TryStreamExt;
+use serde::Serialize;
+use sqlx::PgPool;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::config::{FleetConfig, SeptaConfig};
+
+#[derive(Debug, Serialize)]
+pub struct ClassCount {
+    pub name: String,
+    pub cars: usize,
+    pub married_pair_cars: usize,
+    pub single_cars: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DailyFleet {
+    #[serde(serialize_with = "crate::serde_utils::serialize_date")]
+    pub service_date: NaiveDate,
+    pub car_count: usize,
+    pub cars: Vec<u32>,
+    /// One entry per class name in the fleet table, in the order they're first listed.
+    pub classes: Vec<ClassCount>,
+    /// Cars that don't fall in any range of the fleet table.
+    pub unclassified: usize,
+}
+
+impl DailyFleet {
+    fn new(service_date: NaiveDate, cars: BTreeSet<u32>, fleet: &FleetConfig) -> DailyFleet {
+        let mut classes: Vec<ClassCount> = vec![];
+        for class in fleet.classes.iter() {
+            if !classes.iter().any(|count| count.name == class.name) {
+                classes.push(ClassCount {
+                    name: class.name.clone(),
+                    cars: 0,
+                    married_pair_cars: 0,
+                    single_cars: 0,
+                });
+            }
+        }
+
+        let mut unclassified = 0;
+        for car in cars.iter() {
+            let Some(class) = fleet.classify(*car) else {
+                unclassified += 1;
+                continue;
+            };
+            let count = classes
+                .iter_mut()
+                .find(|count| count.name == class.name)
+                .unwrap();
+            count.cars += 1;
+            if class.married_pair {
+                count.married_pair_cars += 1;
+            } else {
+                count.single_cars += 1;
+            }
+        }
+
+        DailyFleet {
+            service_date,
+            car_count: cars.len(),
+            cars: cars.into_iter().collect(),
+            classes,
+            unclassified,
+        }
+    }
+}
+
+/// The distinct cars listed in `record_cars` on each service day in `[from, to]`, classified by
+/// the fleet table.
+pub async fn daily_usage(
+    pool: PgPool,
+    septa: &SeptaConfig,
+    fleet: &FleetConfig,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> anyhow::Result<Vec<DailyFleet>> {
+    let (start, _) = septa.service_date_bounds(from);
+    let (_, end) = septa.service_date_bounds(to);
+
+    let mut by_day: BTreeMap<NaiveDate, BTreeSet<u32>> = BTreeMap::new();
+    let mut rows = sqlx::query!(
+        r"
+select
+  records.received_at,
+  record_cars.car_number
+from
+  record_cars
+  join records on records.id = record_cars.record_id
+where
+  records.received_at >= $1 and records.received_at < $2
+",
+        start.naive_utc(),
+        end.naive_utc()
+    )
+    .fetch(&pool);
+    while let Some(row) = rows.try_next().await? {
+        let (Some(received_at), Ok(car)) = (row.received_at, u32::try_from(row.car_number)) else {
+            continue;
+        };
+        by_day
+            .entry(septa.service_date(received_at.and_utc()))
+            .or_default()
+            .insert(car);
+    }
+
+    Ok(by_day
+        .into_iter()
+        .map(|(service_date, cars)| DailyFleet::new(service_date, cars, fleet))
+        .collect())
+}
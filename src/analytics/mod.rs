@@ -0,0 +1,9 @@
+pub mod diff;
+pub mod fleet;
+pub mod otp;
+pub mod parquet;
+pub mod schedule;
+pub mod segments;
+
+/// Longest span of service days a single analytics request may cover.
+pub const MAX_RANGE_DAYS: i64 = 366;
@@ -0,0 +1,182 @@
+use chrono::NaiveDate;
+use serde::Serialize;
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+
+use crate::{config::SeptaConfig, septa::train_run::TrainRun};
+
+/// Minutes late a run can finish and still count as within each threshold.
+pub const DEFAULT_THRESHOLDS: [i32; 3] = [5, 10, 15];
+
+#[derive(Debug, Default)]
+pub struct OtpFilter {
+    pub line: Option<String>,
+    pub trainno: Option<String>,
+    pub service: Option<String>,
+}
+
+impl OtpFilter {
+    fn matches(&self, run: &TrainRun) -> bool {
+        self.line
+            .as_ref()
+            .is_none_or(|line| run.lines.contains(line))
+            && self
+                .service
+                .as_ref()
+                .is_none_or(|service| *service == run.service)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ThresholdShare {
+    pub threshold: i32,
+    pub runs: usize,
+    pub share: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OtpSummary {
+    pub runs: usize,
+    pub within: Vec<ThresholdShare>,
+    pub mean_late: Option<f64>,
+    pub median_late: Option<i32>,
+    pub p90_late: Option<i32>,
+}
+
+impl OtpSummary {
+    /// Summarises the terminal lateness of a set of runs. Percentiles use the nearest rank.
+    pub fn from_lateness(mut late: Vec<i32>, thresholds: &[i32]) -> OtpSummary {
+        late.sort_unstable();
+        let runs = late.len();
+        let within = thresholds
+            .iter()
+            .map(|&threshold| {
+                let within = late.iter().take_while(|&&l| l <= threshold).count();
+                ThresholdShare {
+                    threshold,
+                    runs: within,
+                    share: if runs > 0 {
+                        within as f64 / runs as f64
+                    } else {
+                        0.0
+                    },
+                }
+            })
+            .collect();
+        let percentile = |p: f64| {
+            let rank = ((p * runs as f64).ceil() as usize).max(1);
+            late.get(rank - 1).copied()
+        };
+        OtpSummary {
+            runs,
+            within,
+            mean_late: (runs > 0)
+                .then(|| late.iter().map(|&l| l as f64).sum::<f64>() / runs as f64),
+            median_late: percentile(0.5),
+            p90_late: percentile(0.9),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct DailyOtp {
+    #[serde(serialize_with = "crate::serde_utils::serialize_date")]
+    pub service_date: NaiveDate,
+    #[serde(flatten)]
+    pub summary: OtpSummary,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OtpReport {
+    #[serde(serialize_with = "crate::serde_utils::serialize_date")]
+    pub from: NaiveDate,
+    #[serde(serialize_with = "crate::serde_utils::serialize_date")]
+    pub to: NaiveDate,
+    pub thresholds: Vec<i32>,
+    pub overall: OtpSummary,
+    pub days: Vec<DailyOtp>,
+}
+
+/// On-time performance of every run with a service date in `[from, to]`, judged by the lateness
+/// of the last record of each run.
+pub async fn compute(
+    pool: PgPool,
+    config: &SeptaConfig,
+    from: NaiveDate,
+    to: NaiveDate,
+    filter: &OtpFilter,
+    thresholds: Vec<i32>,
+) -> anyhow::Result<OtpReport> {
+    let mut by_day: BTreeMap<NaiveDate, Vec<i32>> = BTreeMap::new();
+    TrainRun::for_each_in_range(pool, config, from, to, filter.trainno.as_deref(), |run| {
+        if filter.matches(&run) {
+            by_day
+                .entry(run.service_date)
+                .or_default()
+                .push(run.final_late);
+        }
+    })
+    .await?;
+
+    let overall =
+        OtpSummary::from_lateness(by_day.values().flatten().copied().collect(), &thresholds);
+    let days = by_day
+        .into_iter()
+        .map(|(service_date, late)| DailyOtp {
+            service_date,
+            summary: OtpSummary::from_lateness(late, &thresholds),
+        })
+        .collect();
+    Ok(OtpReport {
+        from,
+        to,
+        thresholds,
+        overall,
+        days,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shares(summary: &OtpSummary) -> Vec<(i32, usize, f64)> {
+        summary
+            .within
+            .iter()
+            .map(|share| (share.threshold, share.runs, share.share))
+            .collect()
+    }
+
+    #[test]
+    fn thresholds_include_their_edge() {
+        let summary = OtpSummary::from_lateness(vec![6, 0, 5, -2], &[0, 5]);
+        assert_eq!(summary.runs, 4);
+        // Early runs count as on time, and a run 5 minutes late is within 5 but 6 isn't.
+        assert_eq!(shares(&summary), vec![(0, 2, 0.5), (5, 3, 0.75)]);
+        assert_eq!(summary.mean_late, Some(2.25));
+        assert_eq!(summary.median_late, Some(0));
+        assert_eq!(summary.p90_late, Some(6));
+    }
+
+    #[test]
+    fn early_runs_lower_the_mean() {
+        let summary = OtpSummary::from_lateness(vec![-3, -1], &DEFAULT_THRESHOLDS);
+        assert_eq!(
+            shares(&summary),
+            vec![(5, 2, 1.0), (10, 2, 1.0), (15, 2, 1.0)]
+        );
+        assert_eq!(summary.mean_late, Some(-2.0));
+        assert_eq!(summary.median_late, Some(-3));
+    }
+
+    #[test]
+    fn no_runs() {
+        let summary = OtpSummary::from_lateness(vec![], &[5]);
+        assert_eq!(summary.runs, 0);
+        assert_eq!(shares(&summary), vec![(5, 0, 0.0)]);
+        assert_eq!(summary.mean_late, None);
+        assert_eq!(summary.median_late, None);
+        assert_eq!(summary.p90_late, None);
+    }
+}
@@ -0,0 +1,348 @@
+use arrow_array::{
+    ArrayRef, RecordBatch,
+    builder::{Float32Builder, Int32Builder, StringBuilder, TimestampMicrosecondBuilder},
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use chrono::{NaiveDate, NaiveDateTime};
+use futures::TryStreamExt;
+use parquet::{arrow::ArrowWriter, basic::Compression, file::properties::WriterProperties};
+use serde::Serialize;
+use sqlx::{PgPool, Row, postgres::PgRow};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::{
+    config::SeptaConfig,
+    septa::train_view::{RECORD_COLUMNS, select_list},
+};
+
+/// Rows per record batch, and so per row group write.
+const BATCH_ROWS: usize = 8192;
+
+/// The files written for one service day. A table with no rows that day gets no file.
+#[derive(Debug, Serialize)]
+pub struct ExportedDay {
+    #[serde(serialize_with = "crate::serde_utils::serialize_date")]
+    pub service_date: NaiveDate,
+    pub records: usize,
+    pub changes: usize,
+    pub files: Vec<PathBuf>,
+}
+
+fn timestamp_type() -> DataType {
+    DataType::Timestamp(TimeUnit::Microsecond, Some("+00:00".into()))
+}
+
+/// Mirrors `TrainView`.
+fn records_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("id", DataType::Utf8, false),
+        Field::new("file_id", DataType::Utf8, false),
+        Field::new("timestamp", timestamp_type(), false),
+        Field::new("lat", DataType::Float32, true),
+        Field::new("lon", DataType::Float32, true),
+        Field::new("trainno", DataType::Utf8, false),
+        Field::new("service", DataType::Utf8, false),
+        Field::new("dest", DataType::Utf8, false),
+        Field::new("currentstop", DataType::Utf8, false),
+        Field::new("nextstop", DataType::Utf8, false),
+        Field::new("line", DataType::Utf8, false),
+        Field::new("consist", DataType::Utf8, false),
+        Field::new("heading", DataType::Float32, true),
+        Field::new("late", DataType::Int32, false),
+        Field::new("source", DataType::Utf8, false),
+        Field::new("track", DataType::Utf8, true),
+        Field::new("track_change", DataType::Utf8, true),
+        Field::new("currentstop_id", DataType::Utf8, true),
+        Field::new("nextstop_id", DataType::Utf8, true),
+        Field::new("source_id", DataType::Utf8, true),
+        Field::new("dest_id", DataType::Utf8, true),
+    ]))
+}
+
+/// Mirrors `Changed`.
+fn changes_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("id", DataType::Utf8, false),
+        Field::new("trainno", DataType::Utf8, false),
+        Field::new("record_id", DataType::Utf8, false),
+        Field::new("changed_at", timestamp_type(), false),
+        Field::new("field", DataType::Utf8, false),
+        Field::new("old_value", DataType::Utf8, true),
+        Field::new("new_value", DataType::Utf8, true),
+        Field::new("type", DataType::Utf8, false),
+    ]))
+}
+
+/// Columns stored as uuids, written as their string form.
+const UUID_COLUMNS: [&str; 3] = ["id", "file_id", "record_id"];
+
+/// Column builders for a batch, created from the schema so the two can't drift apart.
+enum Column {
+    Uuid(StringBuilder),
+    Text(StringBuilder),
+    Integer(Int32Builder),
+    Real(Float32Builder),
+    Timestamp(TimestampMicrosecondBuilder),
+}
+
+impl Column {
+    fn new(field: &Field) -> Column {
+        match field.data_type() {
+            DataType::Utf8 if UUID_COLUMNS.contains(&field.name().as_str()) => {
+                Column::Uuid(StringBuilder::new())
+            }
+            DataType::Utf8 => Column::Text(StringBuilder::new()),
+            DataType::Int32 => Column::Integer(Int32Builder::new()),
+            DataType::Float32 => Column::Real(Float32Builder::new()),
+            DataType::Timestamp(_, _) => {
+                Column::Timestamp(TimestampMicrosecondBuilder::new().with_timezone("+00:00"))
+            }
+            data_type => unreachable!("no column builder for {data_type}"),
+        }
+    }
+
+    fn append(&mut self, row: &PgRow, name: &str) {
+        match self {
+            Column::Uuid(builder) => {
+                builder.append_option(row.get::<Option<Uuid>, &str>(name).map(|id| id.to_string()))
+            }
+            Column::Text(builder) => builder.append_option(row.get::<Option<String>, &str>(name)),
+            Column::Integer(builder) => builder.append_option(row.get::<Option<i32>, &str>(name)),
+            Column::Real(builder) => builder.append_option(row.get::<Option<f32>, &str>(name)),
+            Column::Timestamp(builder) => builder.append_option(
+                row.get::<Option<NaiveDateTime>, &str>(name)
+                    .map(|timestamp| timestamp.and_utc().timestamp_micros()),
+            ),
+        }
+    }
+
+    fn finish(&mut self) -> ArrayRef {
+        match self {
+            Column::Uuid(builder) | Column::Text(builder) => Arc::new(builder.finish()),
+            Column::Integer(builder) => Arc::new(builder.finish()),
+            Column::Real(builder) => Arc::new(builder.finish()),
+            Column::Timestamp(builder) => Arc::new(builder.finish()),
+        }
+    }
+}
+
+/// Writes batches to `path` as they arrive. Returns whether anything was written; the file
+/// isn't created if there are no batches.
+fn write_batches(
+    path: &Path,
+    schema: SchemaRef,
+    mut batches: mpsc::Receiver<RecordBatch>,
+) -> anyhow::Result<bool> {
+    let Some(first) = batches.blocking_recv() else {
+        return Ok(false);
+    };
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let props = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    let mut writer = ArrowWriter::try_new(std::fs::File::create(path)?, schema, Some(props))?;
+    writer.write(&first)?;
+    while let Some(batch) = batches.blocking_recv() {
+        writer.write(&batch)?;
+    }
+    writer.close()?;
+    Ok(true)
+}
+
+/// Streams the rows of `sql` (bound to the day's `[start, end)`) into a Parquet file at `path`,
+/// through a temporary file so a half written export never looks complete. With no rows, any
+/// earlier file at `path` is removed. Returns the number of rows written.
+async fn export_table(
+    pool: &PgPool,
+    sql: &str,
+    (start, end): (NaiveDateTime, NaiveDateTime),
+    schema: SchemaRef,
+    path: PathBuf,
+) -> anyhow::Result<usize> {
+    // Named per export, so concurrent exports of the same day don't write over each other's.
+    let partial = path.with_extension(format!("parquet.{}.partial", Uuid::new_v4()));
+    let (sender, receiver) = mpsc::channel(2);
+    let writer = tokio::task::spawn_blocking({
+        let schema = schema.clone();
+        let partial = partial.clone();
+        move || write_batches(&partial, schema, receiver)
+    });
+
+    let mut columns: Vec<Column> = schema.fields().iter().map(|f| Column::new(f)).collect();
+    let mut batch_rows = 0;
+    let mut total = 0;
+    let result: anyhow::Result<()> = async {
+        let mut rows = sqlx::query(sql).bind(start).bind(end).fetch(pool);
+        while let Some(row) = rows.try_next().await? {
+            for (column, field) in columns.iter_mut().zip(schema.fields()) {
+                column.append(&row, field.name());
+            }
+            batch_rows += 1;
+            total += 1;
+            if batch_rows == BATCH_ROWS {
+                let arrays = columns.iter_mut().map(Column::finish).collect();
+                sender
+                    .send(RecordBatch::try_new(schema.clone(), arrays)?)
+                    .await?;
+                batch_rows = 0;
+            }
+        }
+        if batch_rows > 0 {
+            let arrays = columns.iter_mut().map(Column::finish).collect();
+            sender
+                .send(RecordBatch::try_new(schema.clone(), arrays)?)
+                .await?;
+        }
+        Ok(())
+    }
+    .await;
+    drop(sender);
+
+    // The writer's error explains a failed send better than the send does.
+    let written = writer.await?.and_then(|written| result.map(|_| written));
+    match written {
+        Ok(true) => tokio::fs::rename(&partial, &path).await?,
+        Ok(false) if tokio::fs::try_exists(&path).await? => tokio::fs::remove_file(&path).await?,
+        Ok(false) => {}
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err(e);
+        }
+    }
+    Ok(total)
+}
+
+/// Writes `records/service_date=YYYY-MM-DD/records.parquet` and the matching `changes` file for
+/// one service day under `dir`, replacing any earlier export of that day.
+pub async fn export_service_day(
+    pool: &PgPool,
+    config: &SeptaConfig,
+    service_date: NaiveDate,
+    dir: &Path,
+) -> anyhow::Result<ExportedDay> {
+    let (start, end) = config.service_date_bounds(service_date);
+    let bounds = (start.naive_utc(), end.naive_utc());
+    let partition = format!("service_date={}", service_date.format("%Y-%m-%d"));
+    let records_path = dir.join("records").join(&partition).join("records.parquet");
+    let changes_path = dir.join("changes").join(&partition).join("changes.parquet");
+
+    let records = export_table(
+        pool,
+        &format!(
+            r#"select
+  {},
+  received_at as timestamp
+from
+    records
+where
+  received_at >= $1 and received_at < $2
+order by
+  received_at, records.id
+"#,
+            select_list(&RECORD_COLUMNS)
+        ),
+        bounds,
+        records_schema(),
+        records_path.clone(),
+    )
+    .await?;
+    let changes = export_table(
+        pool,
+        r#"select
+  id,
+  trainno,
+  record_id,
+  changed_at,
+  field,
+  old_value,
+  new_value,
+  type
+from
+    changes
+where
+  changed_at >= $1 and changed_at < $2
+order by
+  changed_at, id
+"#,
+        bounds,
+        changes_schema(),
+        changes_path.clone(),
+    )
+    .await?;
+
+    let mut files = vec![];
+    if records > 0 {
+        files.push(records_path);
+    }
+    if changes > 0 {
+        files.push(changes_path);
+    }
+    info!(
+        "Exported {} records and {} changes for {}.",
+        records, changes, service_date
+    );
+    Ok(ExportedDay {
+        service_date,
+        records,
+        changes,
+        files,
+    })
+}
+
+/// Exports every service day in `[from, to]`.
+pub async fn export_range(
+    pool: &PgPool,
+    config: &SeptaConfig,
+    from: NaiveDate,
+    to: NaiveDate,
+    dir: &Path,
+) -> anyhow::Result<Vec<ExportedDay>> {
+    let mut days = vec![];
+    for service_date in from.iter_days().take_while(|date| *date <= to) {
+        days.push(export_service_day(pool, config, service_date, dir).await?);
+    }
+    Ok(days)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn records_schema_covers_record_columns() {
+        let schema = records_schema();
+        let fields: BTreeSet<&str> = schema
+            .fields()
+            .iter()
+            .map(|field| field.name().as_str())
+            .collect();
+        // `received_at` is exported as `timestamp`, like `TrainView` names it.
+        let columns: BTreeSet<&str> = RECORD_COLUMNS
+            .into_iter()
+            .filter(|column| *column != "received_at")
+            .chain(["timestamp"])
+            .collect();
+        assert_eq!(fields, columns);
+        assert_eq!(schema.fields().len(), RECORD_COLUMNS.len());
+    }
+
+    #[test]
+    fn no_batches_no_file() {
+        let dir = std::env::temp_dir().join(format!("septa-test-{}", Uuid::new_v4()));
+        let path = dir.join("records.parquet");
+        let (sender, receiver) = mpsc::channel(1);
+        drop(sender);
+
+        assert!(!write_batches(&path, records_schema(), receiver).unwrap());
+        assert!(!path.exists());
+        assert!(!dir.exists());
+    }
+}
@@ -0,0 +1,235 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+
+use crate::{
+    config::SeptaConfig,
+    gtfs::schedule::Trip,
+    septa::{stations::STATIONS, train_run::TrainRun, train_view::TrainView},
+};
+
+/// A train reaching a new `currentstop`, against the schedule and SEPTA's own `late`.
+#[derive(Debug, Serialize)]
+pub struct StopComparison {
+    pub stop: String,
+    pub station_id: Option<String>,
+    /// The scheduled stop this was matched to, null when the trip doesn't stop at the station.
+    pub stop_sequence: Option<i32>,
+    #[serde(serialize_with = "crate::serde_utils::serialize_opt_date_time")]
+    pub scheduled_at: Option<DateTime<Utc>>,
+    /// The first record reporting the stop, so up to a poll interval after the fact.
+    #[serde(serialize_with = "crate::serde_utils::serialize_date_time")]
+    pub observed_at: DateTime<Utc>,
+    /// Minutes between `scheduled_at` and `observed_at`, to a tenth.
+    pub observed_late: Option<f64>,
+    /// `late` as of the first record reporting the stop.
+    pub reported_late: i32,
+}
+
+/// Scheduled vs observed vs reported lateness through one run.
+#[derive(Debug, Serialize)]
+pub struct ScheduleComparison {
+    pub trainno: String,
+    #[serde(serialize_with = "crate::serde_utils::serialize_date")]
+    pub service_date: NaiveDate,
+    /// The GTFS trip the run was linked to, null when the imported schedule has none.
+    pub trip: Option<TripSummary>,
+    pub stops: Vec<StopComparison>,
+    pub mean_observed_late: Option<f64>,
+    pub mean_reported_late: Option<f64>,
+    /// Mean of reported minus observed lateness over the stops that have both. Negative when
+    /// SEPTA reports the train as less late than it is.
+    pub mean_difference: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TripSummary {
+    pub trip_id: String,
+    pub route_id: String,
+    pub trip_headsign: Option<String>,
+}
+
+fn mean(values: impl Iterator<Item = f64>) -> Option<f64> {
+    let (sum, count) = values.fold((0.0, 0), |(sum, count), value| (sum + value, count + 1));
+    (count > 0).then(|| sum / count as f64)
+}
+
+/// Compares the run of `trainno` on `service_date` against the trip it was scheduled as. Returns
+/// `None` when the train has no records that day.
+pub async fn compare_run(
+    pool: PgPool,
+    config: &SeptaConfig,
+    trainno: &str,
+    service_date: NaiveDate,
+) -> anyhow::Result<Option<ScheduleComparison>> {
+    let records = TrainRun::fetch_records(pool.clone(), config, trainno, service_date).await?;
+    if records.is_empty() {
+        return Ok(None);
+    }
+    let trip = Trip::for_train(&pool, service_date, trainno).await?;
+    Ok(Some(compare(trainno, service_date, &records, trip)))
+}
+
+/// Compares a run's records, oldest first, against the trip it was scheduled as, at each record
+/// where `currentstop` changes. The first record is skipped, since a train can sit at its origin
+/// for a while before it leaves. Stations are matched to the trip's stops in order, so a station
+/// the trip calls at twice is matched to each call in turn.
+fn compare(
+    trainno: &str,
+    service_date: NaiveDate,
+    records: &[TrainView],
+    trip: Option<Trip>,
+) -> ScheduleComparison {
+    let mut stops = vec![];
+    let mut next_scheduled = 0;
+    for pair in records.windows(2) {
+        let (previous, record) = (&pair[0], &pair[1]);
+        if record.currentstop == previous.currentstop {
+            continue;
+        }
+        let station_id = record
+            .currentstop_id
+            .clone()
+            .or_else(|| STATIONS.station_id(&record.currentstop));
+        let scheduled = trip.as_ref().and_then(|trip| {
+            let station_id = station_id.as_ref()?;
+            let offset = trip.stops[next_scheduled..]
+                .iter()
+                .position(|stop| stop.station_id.as_ref() == Some(station_id))?;
+            next_scheduled += offset + 1;
+            Some(&trip.stops[next_scheduled - 1])
+        });
+        let scheduled_at = scheduled.and_then(|stop| stop.departure_at.or(stop.arrival_at));
+        stops.push(StopComparison {
+            stop: record.currentstop.clone(),
+            station_id,
+            stop_sequence: scheduled.map(|stop| stop.stop_sequence),
+            scheduled_at,
+            observed_at: record.timestamp,
+            observed_late: scheduled_at.map(|scheduled_at| {
+                ((record.timestamp - scheduled_at).num_seconds() as f64 / 6.0).round() / 10.0
+            }),
+            reported_late: record.late,
+        });
+    }
+
+    ScheduleComparison {
+        trainno: trainno.to_string(),
+        service_date,
+        trip: trip.map(|trip| TripSummary {
+            trip_id: trip.trip_id,
+            route_id: trip.route_id,
+            trip_headsign: trip.trip_headsign,
+        }),
+        mean_observed_late: mean(stops.iter().filter_map(|stop| stop.observed_late)),
+        mean_reported_late: mean(stops.iter().map(|stop| stop.reported_late as f64)),
+        mean_difference: mean(stops.iter().filter_map(|stop| {
+            stop.observed_late
+                .map(|observed| stop.reported_late as f64 - observed)
+        })),
+        stops,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gtfs::schedule::ScheduledStop;
+
+    const START: i64 = 1_792_200_000;
+
+    fn record(stop: &str, secs: i64, late: i32) -> TrainView {
+        let mut record: TrainView = serde_json::from_value(serde_json::json!({
+            "trainno": "1001",
+            "service": "LOCAL",
+            "dest": "Thorndale",
+            "currentstop": stop,
+            "nextstop": "",
+            "line": "Paoli/Thorndale",
+            "consist": "815,816",
+            "late": late,
+            "SOURCE": "30th Street Station",
+        }))
+        .unwrap();
+        record.timestamp = DateTime::from_timestamp(START + secs, 0).unwrap();
+        record
+    }
+
+    fn scheduled(stop_sequence: i32, station_id: &str, secs: i64) -> ScheduledStop {
+        ScheduledStop {
+            stop_sequence,
+            stop_id: format!("stop-{stop_sequence}"),
+            stop_name: station_id.to_string(),
+            station_id: Some(station_id.to_string()),
+            arrival_at: None,
+            departure_at: DateTime::from_timestamp(START + secs, 0),
+        }
+    }
+
+    #[test]
+    fn stations_called_at_twice_match_in_order() {
+        let trip = Trip {
+            trip_id: "PAO_1001".to_string(),
+            route_id: "PAO".to_string(),
+            trip_headsign: None,
+            stops: vec![
+                scheduled(1, "ardmore", 0),
+                scheduled(2, "haverford", 300),
+                scheduled(3, "ardmore", 900),
+            ],
+        };
+        let date = NaiveDate::from_ymd_opt(2026, 10, 18).unwrap();
+        let comparison = compare(
+            "1001",
+            date,
+            &[
+                // Sitting at the origin, so skipped rather than matched to the first call.
+                record("Ardmore", -600, 0),
+                record("Ardmore", 30, 0),
+                record("Haverford", 390, 1),
+                record("Ardmore", 1020, 2),
+            ],
+            Some(trip),
+        );
+
+        let stops: Vec<(&str, Option<i32>, Option<f64>, i32)> = comparison
+            .stops
+            .iter()
+            .map(|stop| {
+                (
+                    stop.stop.as_str(),
+                    stop.stop_sequence,
+                    stop.observed_late,
+                    stop.reported_late,
+                )
+            })
+            .collect();
+        assert_eq!(
+            stops,
+            vec![
+                ("Haverford", Some(2), Some(1.5), 1),
+                ("Ardmore", Some(3), Some(2.0), 2),
+            ]
+        );
+        assert_eq!(comparison.mean_observed_late, Some(1.75));
+        assert_eq!(comparison.mean_reported_late, Some(1.5));
+        assert_eq!(comparison.mean_difference, Some(-0.25));
+        assert_eq!(comparison.trip.unwrap().trip_id, "PAO_1001");
+    }
+
+    #[test]
+    fn runs_without_a_trip_are_only_reported() {
+        let date = NaiveDate::from_ymd_opt(2026, 10, 18).unwrap();
+        let comparison = compare(
+            "1001",
+            date,
+            &[record("Ardmore", 0, 0), record("Haverford", 300, 3)],
+            None,
+        );
+        assert!(comparison.trip.is_none());
+        assert_eq!(comparison.stops.len(), 1);
+        assert_eq!(comparison.stops[0].stop_sequence, None);
+        assert_eq!(comparison.mean_observed_late, None);
+        assert_eq!(comparison.mean_reported_late, Some(3.0));
+    }
+}
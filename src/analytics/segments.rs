@@ -0,0 +1,337 @@
+use chrono::{DateTime, Local, NaiveDate, Timelike, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+
+use crate::{
+    config::SeptaConfig,
+    septa::{stations::STATIONS, train_run::TrainRun},
+};
+
+/// A run moving from one `currentstop` to the next, timed between the first records reporting
+/// each, so it includes the dwell at `from`.
+#[derive(Debug, Serialize)]
+pub struct Segment {
+    pub from: String,
+    pub from_id: Option<String>,
+    pub to: String,
+    pub to_id: Option<String>,
+    #[serde(serialize_with = "crate::serde_utils::serialize_date_time")]
+    pub started_at: DateTime<Utc>,
+    pub secs: i64,
+}
+
+/// Time between the first and last record reporting a stop. Records are only stored when
+/// something changes, so this is a lower bound on how long the train stood there.
+#[derive(Debug, Serialize)]
+pub struct Dwell {
+    pub stop: String,
+    pub station_id: Option<String>,
+    #[serde(serialize_with = "crate::serde_utils::serialize_date_time")]
+    pub arrived_at: DateTime<Utc>,
+    pub secs: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RunTimings {
+    pub trainno: String,
+    #[serde(serialize_with = "crate::serde_utils::serialize_date")]
+    pub service_date: NaiveDate,
+    pub segments: Vec<Segment>,
+    pub dwells: Vec<Dwell>,
+}
+
+impl RunTimings {
+    /// Times the segments and dwells of a run. The first stop is left out of both, since its
+    /// first record is when tracking started rather than when the train got there, and so is
+    /// the dwell at the last stop, where trains sit until they're taken out of service. Records
+    /// stored out of order would give negative times, which are dropped.
+    pub fn from_run(run: &TrainRun) -> RunTimings {
+        let visits = run.stops.get(1..).unwrap_or_default();
+        let segments = visits
+            .windows(2)
+            .map(|pair| {
+                let (from, to) = (&pair[0], &pair[1]);
+                Segment {
+                    from: from.stop.clone(),
+                    from_id: STATIONS.station_id(&from.stop),
+                    to: to.stop.clone(),
+                    to_id: STATIONS.station_id(&to.stop),
+                    started_at: from.first_seen_at,
+                    secs: (to.first_seen_at - from.first_seen_at).num_seconds(),
+                }
+            })
+            .filter(|segment| segment.secs >= 0)
+            .collect();
+        let dwells = visits
+            .split_last()
+            .map_or(&[][..], |(_, visits)| visits)
+            .iter()
+            .map(|visit| Dwell {
+                stop: visit.stop.clone(),
+                station_id: STATIONS.station_id(&visit.stop),
+                arrived_at: visit.first_seen_at,
+                secs: (visit.last_seen_at - visit.first_seen_at).num_seconds(),
+            })
+            .filter(|dwell| dwell.secs >= 0)
+            .collect();
+        RunTimings {
+            trainno: run.trainno.clone(),
+            service_date: run.service_date,
+            segments,
+            dwells,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct TimeSummary {
+    pub count: usize,
+    pub mean_secs: f64,
+    pub median_secs: i64,
+    pub p90_secs: i64,
+    pub max_secs: i64,
+}
+
+impl TimeSummary {
+    /// Percentiles use the nearest rank. `None` when there are no times.
+    fn from_secs(mut secs: Vec<i64>) -> Option<TimeSummary> {
+        secs.sort_unstable();
+        let max_secs = *secs.last()?;
+        let count = secs.len();
+        let percentile = |p: f64| secs[((p * count as f64).ceil() as usize).max(1) - 1];
+        Some(TimeSummary {
+            count,
+            mean_secs: secs.iter().sum::<i64>() as f64 / count as f64,
+            median_secs: percentile(0.5),
+            p90_secs: percentile(0.9),
+            max_secs,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SegmentStats {
+    pub from: String,
+    pub from_id: Option<String>,
+    pub to: String,
+    pub to_id: Option<String>,
+    /// Local hour of day the segments started in.
+    pub hour: u32,
+    #[serde(flatten)]
+    pub summary: TimeSummary,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DwellStats {
+    pub stop: String,
+    pub station_id: Option<String>,
+    /// Local hour of day the train got to the stop.
+    pub hour: u32,
+    #[serde(flatten)]
+    pub summary: TimeSummary,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SegmentReport {
+    #[serde(serialize_with = "crate::serde_utils::serialize_date")]
+    pub from: NaiveDate,
+    #[serde(serialize_with = "crate::serde_utils::serialize_date")]
+    pub to: NaiveDate,
+    pub runs: usize,
+    pub segments: Vec<SegmentStats>,
+    pub dwells: Vec<DwellStats>,
+}
+
+/// A stop as times are grouped by: its station, or its raw name when it isn't in the catalogue.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct StopKey {
+    name: String,
+    station_id: Option<String>,
+}
+
+impl StopKey {
+    fn new(station_id: Option<String>, stop: &str) -> StopKey {
+        let name = STATIONS
+            .lookup(stop)
+            .map_or_else(|| stop.to_string(), |station| station.name.clone());
+        StopKey { name, station_id }
+    }
+}
+
+fn local_hour(at: DateTime<Utc>) -> u32 {
+    at.with_timezone(&Local).hour()
+}
+
+/// Segment and dwell times of every run with a service date in `[from, to]`, by station pair (or
+/// station) and the hour of day they started in, optionally only for runs on `line` or of one
+/// train.
+pub async fn compute(
+    pool: PgPool,
+    config: &SeptaConfig,
+    from: NaiveDate,
+    to: NaiveDate,
+    line: Option<&str>,
+    trainno: Option<&str>,
+) -> anyhow::Result<SegmentReport> {
+    let mut runs = 0;
+    let mut segments: BTreeMap<(StopKey, StopKey, u32), Vec<i64>> = BTreeMap::new();
+    let mut dwells: BTreeMap<(StopKey, u32), Vec<i64>> = BTreeMap::new();
+    TrainRun::for_each_in_range(pool, config, from, to, trainno, |run| {
+        if line.is_some_and(|line| !run.lines.iter().any(|l| l == line)) {
+            return;
+        }
+        runs += 1;
+        let timings = RunTimings::from_run(&run);
+        for segment in timings.segments {
+            let key = (
+                StopKey::new(segment.from_id, &segment.from),
+                StopKey::new(segment.to_id, &segment.to),
+                local_hour(segment.started_at),
+            );
+            segments.entry(key).or_default().push(segment.secs);
+        }
+        for dwell in timings.dwells {
+            let key = (
+                StopKey::new(dwell.station_id, &dwell.stop),
+                local_hour(dwell.arrived_at),
+            );
+            dwells.entry(key).or_default().push(dwell.secs);
+        }
+    })
+    .await?;
+
+    Ok(SegmentReport {
+        from,
+        to,
+        runs,
+        segments: segments
+            .into_iter()
+            .filter_map(|((from, to, hour), secs)| {
+                Some(SegmentStats {
+                    from: from.name,
+                    from_id: from.station_id,
+                    to: to.name,
+                    to_id: to.station_id,
+                    hour,
+                    summary: TimeSummary::from_secs(secs)?,
+                })
+            })
+            .collect(),
+        dwells: dwells
+            .into_iter()
+            .filter_map(|((stop, hour), secs)| {
+                Some(DwellStats {
+                    stop: stop.name,
+                    station_id: stop.station_id,
+                    hour,
+                    summary: TimeSummary::from_secs(secs)?,
+                })
+            })
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::septa::train_view::TrainView;
+
+    /// A record of train 1001 at `stop`, `secs` into the run.
+    fn record(stop: &str, secs: i64) -> TrainView {
+        let mut record: TrainView = serde_json::from_value(serde_json::json!({
+            "trainno": "1001",
+            "service": "LOCAL",
+            "dest": "Thorndale",
+            "currentstop": stop,
+            "nextstop": "",
+            "line": "Paoli/Thorndale",
+            "consist": "815,816",
+            "late": 0,
+            "SOURCE": "30th Street Station",
+        }))
+        .unwrap();
+        record.timestamp = DateTime::from_timestamp(1_792_200_000 + secs, 0).unwrap();
+        record
+    }
+
+    /// `(from, to, secs)` by station id.
+    type SegmentTime = (String, String, i64);
+    /// `(stop, secs)` by station id.
+    type DwellTime = (String, i64);
+
+    /// The segments and dwells of a run over `records`.
+    fn timings(records: &[TrainView]) -> (Vec<SegmentTime>, Vec<DwellTime>) {
+        let date = NaiveDate::from_ymd_opt(2026, 10, 18).unwrap();
+        let timings = RunTimings::from_run(&TrainRun::from_records(date, records).unwrap());
+        (
+            timings
+                .segments
+                .into_iter()
+                .map(|segment| {
+                    (
+                        segment.from_id.unwrap(),
+                        segment.to_id.unwrap(),
+                        segment.secs,
+                    )
+                })
+                .collect(),
+            timings
+                .dwells
+                .into_iter()
+                .map(|dwell| (dwell.station_id.unwrap(), dwell.secs))
+                .collect(),
+        )
+    }
+
+    fn segment(from: &str, to: &str, secs: i64) -> SegmentTime {
+        (from.to_string(), to.to_string(), secs)
+    }
+
+    fn dwell(stop: &str, secs: i64) -> DwellTime {
+        (stop.to_string(), secs)
+    }
+
+    #[test]
+    fn repeated_stops_and_gaps() {
+        let (segments, dwells) = timings(&[
+            record("Overbrook", 0),
+            record("Ardmore", 100),
+            record("Ardmore", 160),
+            record("Ardmore", 190),
+            record("Haverford", 300),
+            // Not heard from for 20 minutes.
+            record("Bryn Mawr", 1500),
+            record("Bryn Mawr", 1600),
+        ]);
+        // Nothing from the first stop, which the run was already at when it was first seen.
+        assert_eq!(
+            segments,
+            vec![
+                segment("ardmore", "haverford", 200),
+                segment("haverford", "bryn-mawr", 1200),
+            ]
+        );
+        // Nor the last, where the train sits until it's taken out of service.
+        assert_eq!(dwells, vec![dwell("ardmore", 90), dwell("haverford", 0)]);
+    }
+
+    #[test]
+    fn out_of_order_records_are_dropped() {
+        let (segments, dwells) = timings(&[
+            record("Overbrook", 0),
+            record("Ardmore", 100),
+            record("Ardmore", 80),
+            record("Haverford", 90),
+            record("Bryn Mawr", 200),
+        ]);
+        assert_eq!(segments, vec![segment("haverford", "bryn-mawr", 110)]);
+        assert_eq!(dwells, vec![dwell("haverford", 0)]);
+    }
+
+    #[test]
+    fn runs_that_never_move() {
+        let (segments, dwells) = timings(&[record("Overbrook", 0), record("Overbrook", 60)]);
+        assert!(segments.is_empty() && dwells.is_empty());
+    }
+}
@@ -0,0 +1,395 @@
+use anyhow::{anyhow, bail};
+use chrono::{DateTime, Local, NaiveDate, NaiveTime, TimeZone, Utc};
+use serde::Deserialize;
+use std::path::Path;
+
+pub const DEFAULT_CONFIG_FILE: &str = "./config.toml";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub septa: SeptaConfig,
+    pub files: FilesConfig,
+    pub api: ApiConfig,
+    pub fleet: FleetConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            host: "0.0.0.0".to_string(),
+            port: 8081,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SeptaConfig {
+    pub url: String,
+    pub poll_interval_secs: u64,
+    pub request_timeout_secs: u64,
+    pub max_backoff_secs: u64,
+    pub breaker_threshold: u32,
+    pub breaker_cooldown_secs: u64,
+    /// Local hour at which a new service day starts. Trains running past midnight still belong
+    /// to the previous day until then.
+    pub service_day_cutoff_hour: u32,
+}
+
+impl Default for SeptaConfig {
+    fn default() -> Self {
+        SeptaConfig {
+            url: "https://www3.septa.org/api/TrainView/index.php".to_string(),
+            poll_interval_secs: 5,
+            request_timeout_secs: 10,
+            max_backoff_secs: 5 * 60,
+            breaker_threshold: 10,
+            breaker_cooldown_secs: 10 * 60,
+            service_day_cutoff_hour: 2,
+        }
+    }
+}
+
+impl SeptaConfig {
+    /// The instant the service day on the calendar date of `day` starts.
+    pub fn service_day_start<Tz: TimeZone>(&self, day: DateTime<Tz>) -> DateTime<Utc> {
+        self.service_date_start(day.with_timezone(&Local).date_naive())
+    }
+
+    /// The service date an instant belongs to. Anything before the cutoff hour counts towards
+    /// the previous day.
+    pub fn service_date(&self, at: DateTime<Utc>) -> NaiveDate {
+        (at.with_timezone(&Local) - chrono::Duration::hours(self.service_day_cutoff_hour as i64))
+            .date_naive()
+    }
+
+    /// `[start, end)` of the given service date.
+    pub fn service_date_bounds(&self, date: NaiveDate) -> (DateTime<Utc>, DateTime<Utc>) {
+        let next = date.succ_opt().unwrap_or(date);
+        (self.service_date_start(date), self.service_date_start(next))
+    }
+
+    fn service_date_start(&self, date: NaiveDate) -> DateTime<Utc> {
+        let cutoff = NaiveTime::from_hms_opt(self.service_day_cutoff_hour, 0, 0).unwrap();
+        let start = date.and_time(cutoff);
+        // The cutoff can fall inside a DST gap, in which case the day starts an hour later.
+        Local
+            .from_local_datetime(&start)
+            .earliest()
+            .or_else(|| {
+                Local
+                    .from_local_datetime(&(start + chrono::Duration::hours(1)))
+                    .earliest()
+            })
+            .unwrap()
+            .to_utc()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilesConfig {
+    pub output_dir: String,
+    pub retention_days: u64,
+    /// Where Parquet exports are written, one partition per service day.
+    pub parquet_dir: String,
+}
+
+impl Default for FilesConfig {
+    fn default() -> Self {
+        FilesConfig {
+            output_dir: "./files".to_string(),
+            retention_days: 7,
+            parquet_dir: "./parquet".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiConfig {
+    pub default_limit: i64,
+    pub min_limit: i64,
+    pub max_limit: i64,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        ApiConfig {
+            default_limit: 100,
+            min_limit: 1,
+            max_limit: 300,
+        }
+    }
+}
+
+impl ApiConfig {
+    /// Enforces the following restriction on passed limit option: `[min_limit, max_limit]`
+    pub fn enforce_limit_bounds(&self, limit: Option<i64>) -> i64 {
+        limit
+            .unwrap_or(self.default_limit)
+            .clamp(self.min_limit, self.max_limit)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FleetClass {
+    pub name: String,
+    /// First car number in the range, inclusive.
+    pub first: u32,
+    /// Last car number in the range, inclusive.
+    pub last: u32,
+    #[serde(default)]
+    pub married_pair: bool,
+}
+
+impl FleetClass {
+    fn new(name: &str, first: u32, last: u32, married_pair: bool) -> Self {
+        FleetClass {
+            name: name.to_string(),
+            first,
+            last,
+            married_pair,
+        }
+    }
+
+    pub fn contains(&self, car: u32) -> bool {
+        (self.first..=self.last).contains(&car)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FleetConfig {
+    /// Car number ranges and the class of car they belong to. A class can have several ranges.
+    pub classes: Vec<FleetClass>,
+}
+
+impl Default for FleetConfig {
+    fn default() -> Self {
+        FleetConfig {
+            classes: vec![
+                FleetClass::new("Silverliner III", 220, 239, false),
+                FleetClass::new("Silverliner IV", 101, 188, true),
+                FleetClass::new("Silverliner IV", 306, 399, true),
+                FleetClass::new("Silverliner IV", 417, 460, true),
+                FleetClass::new("Silverliner IV", 276, 305, false),
+                FleetClass::new("Silverliner IV", 400, 416, false),
+                FleetClass::new("Silverliner V", 701, 738, false),
+                FleetClass::new("Silverliner V", 801, 882, true),
+            ],
+        }
+    }
+}
+
+impl FleetConfig {
+    pub fn classify(&self, car: u32) -> Option<&FleetClass> {
+        self.classes.iter().find(|class| class.contains(car))
+    }
+}
+
+macro_rules! env_override {
+    ($key:literal, $field:expr) => {
+        if let Ok(val) = dotenvy::var($key) {
+            $field = val
+                .parse()
+                .map_err(|e| anyhow!("Invalid value for {}: {:?}", $key, e))?;
+        }
+    };
+}
+
+impl Config {
+    /// Loads the file named by `CONFIG_FILE` (or `./config.toml` if it exists), applies any
+    /// environment variable overrides and validates the result.
+    pub fn load() -> anyhow::Result<Config> {
+        let mut config = match dotenvy::var("CONFIG_FILE") {
+            Ok(path) => Config::from_file(&path)?,
+            Err(_) if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Config::from_file(DEFAULT_CONFIG_FILE)?
+            }
+            Err(_) => Config::default(),
+        };
+        config.apply_env_overrides()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Config> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Unable to read config file {:?}: {e}", path))?;
+        toml::from_str(&contents).map_err(|e| anyhow!("Invalid config file {:?}: {e}", path))
+    }
+
+    fn apply_env_overrides(&mut self) -> anyhow::Result<()> {
+        env_override!("BIND_HOST", self.server.host);
+        env_override!("BIND_PORT", self.server.port);
+        env_override!("SEPTA_URL", self.septa.url);
+        env_override!("POLL_INTERVAL_SECS", self.septa.poll_interval_secs);
+        env_override!("FETCH_TIMEOUT_SECS", self.septa.request_timeout_secs);
+        env_override!("POLL_MAX_BACKOFF_SECS", self.septa.max_backoff_secs);
+        env_override!("POLL_BREAKER_THRESHOLD", self.septa.breaker_threshold);
+        env_override!(
+            "POLL_BREAKER_COOLDOWN_SECS",
+            self.septa.breaker_cooldown_secs
+        );
+        env_override!(
+            "SERVICE_DAY_CUTOFF_HOUR",
+            self.septa.service_day_cutoff_hour
+        );
+        env_override!("FILES_OUTPUT_DIR", self.files.output_dir);
+        env_override!("FILE_RETENTION_DAYS", self.files.retention_days);
+        env_override!("PARQUET_DIR", self.files.parquet_dir);
+        env_override!("DEFAULT_LIMIT", self.api.default_limit);
+        env_override!("MIN_LIMIT", self.api.min_limit);
+        env_override!("MAX_LIMIT", self.api.max_limit);
+        Ok(())
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Err(e) = reqwest::Url::parse(&self.septa.url) {
+            bail!("septa.url is not a valid url: {e}");
+        }
+        if self.septa.poll_interval_secs == 0 {
+            bail!("septa.poll_interval_secs must be greater than 0");
+        }
+        if self.septa.request_timeout_secs == 0 {
+            bail!("septa.request_timeout_secs must be greater than 0");
+        }
+        if self.septa.max_backoff_secs < self.septa.poll_interval_secs {
+            bail!("septa.max_backoff_secs must be at least septa.poll_interval_secs");
+        }
+        if self.septa.breaker_threshold == 0 {
+            bail!("septa.breaker_threshold must be greater than 0");
+        }
+        if self.septa.service_day_cutoff_hour > 23 {
+            bail!("septa.service_day_cutoff_hour must be in [0, 23]");
+        }
+        if self.files.output_dir.is_empty() {
+            bail!("files.output_dir must not be empty");
+        }
+        if self.files.parquet_dir.is_empty() {
+            bail!("files.parquet_dir must not be empty");
+        }
+        if self.files.retention_days == 0 {
+            bail!("files.retention_days must be greater than 0");
+        }
+        if self.api.min_limit < 1 {
+            bail!("api.min_limit must be at least 1");
+        }
+        if !(self.api.min_limit..=self.api.max_limit).contains(&self.api.default_limit) {
+            bail!("api.default_limit must be within [api.min_limit, api.max_limit]");
+        }
+        for (i, class) in self.fleet.classes.iter().enumerate() {
+            if class.name.is_empty() {
+                bail!("fleet.classes[{i}].name must not be empty");
+            }
+            if class.first > class.last {
+                bail!("fleet.classes[{i}].first must not be greater than last");
+            }
+            if let Some(other) = self.fleet.classes[..i]
+                .iter()
+                .find(|other| other.first <= class.last && class.first <= other.last)
+            {
+                bail!(
+                    "fleet.classes[{i}] ({}-{}) overlaps {} ({}-{})",
+                    class.first,
+                    class.last,
+                    other.name,
+                    other.first,
+                    other.last
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(toml: &str) -> Config {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn invalid(config: &Config) -> String {
+        config.validate().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn defaults_and_example_are_valid() {
+        Config::default().validate().unwrap();
+        let example =
+            Config::from_file(Path::new(env!("CARGO_MANIFEST_DIR")).join("config.example.toml"))
+                .unwrap();
+        example.validate().unwrap();
+    }
+
+    #[test]
+    fn env_overrides_beat_the_file() {
+        let mut config = config("[septa]\npoll_interval_secs = 30\n\n[server]\nport = 9000\n");
+        // SAFETY: no other test reads or writes these variables.
+        unsafe { std::env::set_var("POLL_INTERVAL_SECS", "15") };
+        config.apply_env_overrides().unwrap();
+        assert_eq!(config.septa.poll_interval_secs, 15);
+        assert_eq!(config.server.port, 9000);
+
+        unsafe { std::env::set_var("POLL_INTERVAL_SECS", "soon") };
+        let err = config.apply_env_overrides().unwrap_err().to_string();
+        unsafe { std::env::remove_var("POLL_INTERVAL_SECS") };
+        assert!(
+            err.starts_with("Invalid value for POLL_INTERVAL_SECS"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn zero_poll_interval_is_rejected() {
+        let config = config("[septa]\npoll_interval_secs = 0\n");
+        assert_eq!(
+            invalid(&config),
+            "septa.poll_interval_secs must be greater than 0"
+        );
+    }
+
+    #[test]
+    fn overlapping_fleet_ranges_are_rejected() {
+        let config = config(
+            r#"
+            [[fleet.classes]]
+            name = "Silverliner IV"
+            first = 101
+            last = 188
+
+            [[fleet.classes]]
+            name = "Silverliner V"
+            first = 188
+            last = 200
+            "#,
+        );
+        assert_eq!(
+            invalid(&config),
+            "fleet.classes[1] (188-200) overlaps Silverliner IV (101-188)"
+        );
+
+        let mut config = config;
+        config.fleet.classes[1].first = 189;
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(toml::from_str::<Config>("[septa]\npoll_interval = 5\n").is_err());
+    }
+}
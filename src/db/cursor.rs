@@ -0,0 +1,331 @@
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use uuid::Uuid;
+
+use crate::db::QueryOrdering;
+
+/// A position in a list of rows, keyed on a timestamp and an id, ex: `(received_at, records.id)`,
+/// since plenty of rows share a timestamp. Clients only ever see it encoded.
+#[derive(Debug, Clone, Copy)]
+pub struct Cursor {
+    received_at: NaiveDateTime,
+    id: Uuid,
+    /// The ordering of the pages this cursor walks through.
+    order: QueryOrdering,
+    /// Walks against `order`, back towards the pages already seen.
+    backward: bool,
+}
+
+#[derive(Debug)]
+pub struct DecodeCursorError;
+impl std::fmt::Display for DecodeCursorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid cursor")
+    }
+}
+impl std::error::Error for DecodeCursorError {}
+
+impl Cursor {
+    /// `received_at` micros, then the id, then a byte of flags.
+    const ENCODED_LEN: usize = 8 + 16 + 1;
+
+    pub fn encode(&self) -> String {
+        let mut bytes = Vec::with_capacity(Self::ENCODED_LEN);
+        bytes.extend_from_slice(&self.received_at.and_utc().timestamp_micros().to_be_bytes());
+        bytes.extend_from_slice(self.id.as_bytes());
+        let order = match self.order {
+            QueryOrdering::ASC => 0,
+            QueryOrdering::DESC => 1,
+        };
+        bytes.push(order | (self.backward as u8) << 1);
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    pub fn decode(encoded: &str) -> Result<Cursor, DecodeCursorError> {
+        let bytes = URL_SAFE_NO_PAD
+            .decode(encoded)
+            .map_err(|_| DecodeCursorError)?;
+        if bytes.len() != Self::ENCODED_LEN || bytes[24] > 0b11 {
+            return Err(DecodeCursorError);
+        }
+        let micros = i64::from_be_bytes(bytes[..8].try_into().unwrap());
+        Ok(Cursor {
+            received_at: DateTime::from_timestamp_micros(micros)
+                .ok_or(DecodeCursorError)?
+                .naive_utc(),
+            id: Uuid::from_slice(&bytes[8..24]).map_err(|_| DecodeCursorError)?,
+            order: match bytes[24] & 1 {
+                0 => QueryOrdering::ASC,
+                _ => QueryOrdering::DESC,
+            },
+            backward: bytes[24] & 0b10 != 0,
+        })
+    }
+}
+
+impl serde::Serialize for Cursor {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.encode())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Cursor {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s: String = serde::Deserialize::deserialize(deserializer)?;
+        Cursor::decode(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+/// A page of records and the cursors either side of it.
+#[derive(Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<Cursor>,
+    pub prev_cursor: Option<Cursor>,
+}
+
+/// Keyset pagination over rows ordered by a timestamp column, then an id column. Records
+/// (`received_at`, `records.id`) unless set with [`Pagination::on`].
+#[derive(Debug, Clone, Copy)]
+pub struct Pagination {
+    limit: i64,
+    order: QueryOrdering,
+    cursor: Option<Cursor>,
+    time_column: &'static str,
+    id_column: &'static str,
+}
+
+impl Pagination {
+    /// A cursor carries the ordering it was made with, which wins over `order`.
+    pub fn new(limit: i64, order: Option<QueryOrdering>, cursor: Option<Cursor>) -> Self {
+        Pagination {
+            limit,
+            order: cursor
+                .map(|cursor| cursor.order)
+                .or(order)
+                .unwrap_or(QueryOrdering::DESC),
+            cursor,
+            time_column: "received_at",
+            id_column: "records.id",
+        }
+    }
+
+    /// Pages on other columns than those of `records`.
+    pub fn on(self, time_column: &'static str, id_column: &'static str) -> Self {
+        Pagination {
+            time_column,
+            id_column,
+            ..self
+        }
+    }
+
+    fn backward(&self) -> bool {
+        self.cursor.is_some_and(|cursor| cursor.backward)
+    }
+
+    /// The order rows are actually fetched in, flipped when walking backward.
+    fn fetch_order(&self) -> QueryOrdering {
+        match (self.order, self.backward()) {
+            (QueryOrdering::ASC, false) | (QueryOrdering::DESC, true) => QueryOrdering::ASC,
+            (QueryOrdering::DESC, false) | (QueryOrdering::ASC, true) => QueryOrdering::DESC,
+        }
+    }
+
+    /// Pushes the condition for rows past the cursor, prefixed with `prefix` (` where ` or
+    /// ` and `).
+    pub fn push_condition(
+        &self,
+        builder: &mut sqlx::QueryBuilder<'_, sqlx::Postgres>,
+        prefix: &str,
+    ) {
+        let Some(cursor) = self.cursor else {
+            return;
+        };
+        builder.push(prefix);
+        builder.push(format!(
+            "({}, {}) {} (",
+            self.time_column,
+            self.id_column,
+            match self.fetch_order() {
+                QueryOrdering::ASC => ">",
+                QueryOrdering::DESC => "<",
+            }
+        ));
+        builder.push_bind(cursor.received_at);
+        builder.push(", ");
+        builder.push_bind(cursor.id);
+        builder.push(")");
+    }
+
+    /// Pushes the ordering and limit. One extra row is fetched to tell if there's another page.
+    pub fn push_order_and_limit(&self, builder: &mut sqlx::QueryBuilder<'_, sqlx::Postgres>) {
+        let order = self.fetch_order();
+        builder.push(format!(
+            " ORDER BY {} {order}, {} {order}",
+            self.time_column, self.id_column
+        ));
+        builder.push(" LIMIT ");
+        builder.push_bind(self.limit + 1);
+    }
+
+    /// Trims the fetched rows to a page in `order`, and works out its cursors from the
+    /// `(timestamp, id)` of its first and last rows.
+    pub fn page<T>(&self, mut items: Vec<T>, key: impl Fn(&T) -> (DateTime<Utc>, Uuid)) -> Page<T> {
+        let has_more = items.len() as i64 > self.limit;
+        items.truncate(self.limit as usize);
+        let backward = self.backward();
+        if backward {
+            items.reverse();
+        }
+
+        let cursor = |item: &T, backward: bool| {
+            let (received_at, id) = key(item);
+            Cursor {
+                received_at: received_at.naive_utc(),
+                id,
+                order: self.order,
+                backward,
+            }
+        };
+        // Whichever way we came from, there's a page back there.
+        let (has_next, has_prev) = if backward {
+            (true, has_more)
+        } else {
+            (has_more, self.cursor.is_some())
+        };
+        Page {
+            next_cursor: items
+                .last()
+                .filter(|_| has_next)
+                .map(|item| cursor(item, false)),
+            prev_cursor: items
+                .first()
+                .filter(|_| has_prev)
+                .map(|item| cursor(item, true)),
+            items,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::{PgPool, Row};
+
+    fn cursor(order: QueryOrdering, backward: bool) -> Cursor {
+        Cursor {
+            received_at: DateTime::from_timestamp_micros(1_792_201_234_567_890)
+                .unwrap()
+                .naive_utc(),
+            id: Uuid::new_v4(),
+            order,
+            backward,
+        }
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        for order in [QueryOrdering::ASC, QueryOrdering::DESC] {
+            for backward in [false, true] {
+                let cursor = cursor(order, backward);
+                let decoded = Cursor::decode(&cursor.encode()).unwrap();
+                assert_eq!(decoded.received_at, cursor.received_at);
+                assert_eq!(decoded.id, cursor.id);
+                assert_eq!(decoded.order, order);
+                assert_eq!(decoded.backward, backward);
+            }
+        }
+    }
+
+    #[test]
+    fn bad_cursors_are_rejected() {
+        let bytes = URL_SAFE_NO_PAD
+            .decode(cursor(QueryOrdering::ASC, false).encode())
+            .unwrap();
+        let with = |edit: fn(&mut Vec<u8>)| {
+            let mut bytes = bytes.clone();
+            edit(&mut bytes);
+            URL_SAFE_NO_PAD.encode(bytes)
+        };
+        let bad = [
+            String::new(),
+            "not a cursor!".to_string(),
+            with(|bytes| bytes.truncate(24)),
+            with(|bytes| bytes.push(0)),
+            // Unknown flags.
+            with(|bytes| bytes[24] = 0b100),
+            // Micros past what a timestamp can hold.
+            with(|bytes| bytes[..8].copy_from_slice(&i64::MAX.to_be_bytes())),
+        ];
+        for encoded in bad {
+            assert!(Cursor::decode(&encoded).is_err(), "decoded {encoded:?}");
+        }
+    }
+
+    /// Fetches a page of `rows` the way the api fetches records.
+    async fn fetch(
+        pool: &PgPool,
+        rows: &[(DateTime<Utc>, Uuid)],
+        pagination: Pagination,
+    ) -> Page<(DateTime<Utc>, Uuid)> {
+        let mut builder = sqlx::QueryBuilder::new("select received_at, id from unnest(");
+        builder.push_bind(rows.iter().map(|row| row.0.naive_utc()).collect::<Vec<_>>());
+        builder.push("::timestamp[], ");
+        builder.push_bind(rows.iter().map(|row| row.1).collect::<Vec<_>>());
+        builder.push("::uuid[]) as rows(received_at, id)");
+        let pagination = pagination.on("received_at", "id");
+        pagination.push_condition(&mut builder, " where ");
+        pagination.push_order_and_limit(&mut builder);
+        let items = builder
+            .build()
+            .fetch_all(pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| {
+                (
+                    row.get::<NaiveDateTime, _>("received_at").and_utc(),
+                    row.get("id"),
+                )
+            })
+            .collect();
+        pagination.page(items, |row| *row)
+    }
+
+    #[sqlx::test]
+    async fn pages_through_rows_sharing_a_timestamp(pool: PgPool) {
+        let at = |secs: i64| DateTime::from_timestamp(1_792_200_000 + secs, 0).unwrap();
+        let rows: Vec<(DateTime<Utc>, Uuid)> = [0, 10, 10, 10, 10, 20]
+            .map(|secs| (at(secs), Uuid::new_v4()))
+            .into();
+
+        for order in [QueryOrdering::ASC, QueryOrdering::DESC] {
+            let mut expected = rows.clone();
+            expected.sort();
+            if order == QueryOrdering::DESC {
+                expected.reverse();
+            }
+
+            // Forward to the last page, two rows at a time, then back to the first.
+            let mut page = fetch(&pool, &rows, Pagination::new(2, Some(order), None)).await;
+            let mut forward = vec![page.items.clone()];
+            while let Some(next) = page.next_cursor {
+                page = fetch(&pool, &rows, Pagination::new(2, None, Some(next))).await;
+                forward.push(page.items.clone());
+            }
+            let mut backward = vec![page.items.clone()];
+            while let Some(prev) = page.prev_cursor {
+                page = fetch(&pool, &rows, Pagination::new(2, None, Some(prev))).await;
+                backward.push(page.items.clone());
+            }
+            backward.reverse();
+
+            assert_eq!(forward.len(), 3);
+            assert_eq!(forward.concat(), expected);
+            assert_eq!(backward, forward);
+        }
+    }
+}
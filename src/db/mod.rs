@@ -1,5 +1,6 @@
 use sqlx::{PgPool, postgres::PgConnectOptions};
 
+pub mod cursor;
 pub mod tracking;
 
 pub async fn init() -> anyhow::Result<PgPool> {
@@ -12,7 +13,8 @@ pub async fn init() -> anyhow::Result<PgPool> {
     PgPool::connect_with(opts).await.map_err(anyhow::Error::msg)
 }
 
-#[derive(Debug, Clone, Copy)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum QueryOrdering {
     ASC,
     DESC,
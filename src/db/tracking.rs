@@ -1,10 +1,12 @@
 use std::{fmt::Display, sync::Arc};
 
-use chrono::{DateTime, TimeZone, Utc};
-use serde::Serialize;
-use sqlx::{PgPool, prelude::FromRow};
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Row, Transaction, postgres::PgRow, prelude::FromRow};
 use uuid::Uuid;
 
+use crate::db::cursor::{Page, Pagination};
+
 #[derive(Debug, Serialize, Clone)]
 #[serde(untagged)]
 pub enum Value {
@@ -23,7 +25,6 @@ impl Value {
         }
     }
 
-    #[allow(unused)]
     pub fn to_sql_fields(&self) -> (String, String) {
         match self {
             Value::String(val) => ("String".into(), val.clone()),
@@ -31,6 +32,16 @@ impl Value {
             Value::Int(val) => ("Integer".into(), val.to_string()),
         }
     }
+
+    /// Rebuilds a value from the `type` column and the stored string, falling back to a
+    /// `String` if the stored value can't be parsed as the recorded type.
+    pub fn from_sql_fields(_type: &str, val: String) -> Self {
+        match _type {
+            "Float" => val.parse().map(Value::Float).unwrap_or(Value::String(val)),
+            "Integer" => val.parse().map(Value::Int).unwrap_or(Value::String(val)),
+            _ => Value::String(val),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Clone, FromRow)]
@@ -47,6 +58,198 @@ pub struct Changed {
     pub _type: String,
 }
 
+impl Changed {
+    /// Maps a row selecting the columns of `changes`.
+    pub fn from_row(row: &PgRow) -> Changed {
+        let _type: String = row.get("type");
+        Changed {
+            id: row.get("id"),
+            trainno: row.get("trainno"),
+            record_id: row.get("record_id"),
+            changed_at: row.get::<NaiveDateTime, &str>("changed_at").and_utc(),
+            field: row.get("field"),
+            old_value: Value::from_sql_fields(
+                &_type,
+                row.get::<Option<String>, &str>("old_value")
+                    .unwrap_or_default(),
+            ),
+            new_value: Value::from_sql_fields(
+                &_type,
+                row.get::<Option<String>, &str>("new_value")
+                    .unwrap_or_default(),
+            ),
+            _type,
+        }
+    }
+
+    pub async fn commit_changes(
+        changes: &[Changed],
+        tx: &mut Transaction<'_, Postgres>,
+    ) -> anyhow::Result<u64> {
+        if changes.is_empty() {
+            return Ok(0);
+        }
+        let mut builder = sqlx::QueryBuilder::new(
+            r" INSERT INTO changes
+    (id, trainno, record_id, changed_at, field, old_value, new_value, type) ",
+        );
+        builder.push_values(changes.iter(), |mut a, change| {
+            a.push_bind(change.id)
+                .push_bind(&change.trainno)
+                .push_bind(change.record_id)
+                .push_bind(change.changed_at.naive_utc())
+                .push_bind(&change.field)
+                .push_bind(change.old_value.to_sql_fields().1)
+                .push_bind(change.new_value.to_sql_fields().1)
+                .push_bind(&change._type);
+        });
+        let inserted = builder.build().execute(&mut **tx).await?;
+        Ok(inserted.rows_affected())
+    }
+
+    /// Fetches a page of a train's changes. Every change from one poll shares a `changed_at`, so
+    /// they're paged on `(changed_at, id)`.
+    pub async fn fetch_for_train(
+        pool: PgPool,
+        trainno: &str,
+        pagination: Pagination,
+        before: Option<DateTime<Utc>>,
+        after: Option<DateTime<Utc>>,
+    ) -> anyhow::Result<Page<Changed>> {
+        let pagination = pagination.on("changed_at", "id");
+        let mut builder = sqlx::QueryBuilder::new(
+            r#"select
+  id,
+  trainno,
+  record_id,
+  changed_at,
+  field,
+  old_value,
+  new_value,
+  type
+from
+    changes
+"#,
+        );
+        builder.push(" WHERE trainno = ");
+        builder.push_bind(trainno);
+
+        if let Some(before) = before {
+            builder.push(" and changed_at < ");
+            builder.push_bind(before.naive_utc());
+        }
+        if let Some(after) = after {
+            builder.push(" and changed_at > ");
+            builder.push_bind(after.naive_utc());
+        }
+        pagination.push_condition(&mut builder, " and ");
+        pagination.push_order_and_limit(&mut builder);
+
+        let results = builder.build().fetch_all(&pool).await?;
+        let changes = results.iter().map(Changed::from_row).collect();
+        Ok(pagination.page(changes, |change: &Changed| (change.changed_at, change.id)))
+    }
+}
+
+/// Optional filters for the changes a subscriber is interested in.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct ChangeFilter {
+    pub trainno: Option<String>,
+    pub line: Option<String>,
+    pub field: Option<String>,
+}
+
+impl ChangeFilter {
+    pub fn matches(&self, event: &ChangeEvent) -> bool {
+        self.trainno
+            .as_ref()
+            .is_none_or(|trainno| *trainno == event.change.trainno)
+            && self.line.as_ref().is_none_or(|line| *line == event.line)
+            && self
+                .field
+                .as_ref()
+                .is_none_or(|field| *field == event.change.field)
+    }
+}
+
+/// A change as published to live subscribers, along with the line the train reported with it.
+#[derive(Debug, Serialize, Clone)]
+pub struct ChangeEvent {
+    #[serde(flatten)]
+    pub change: Changed,
+    pub line: String,
+}
+
+impl ChangeEvent {
+    /// When a persisted change happened, if it exists.
+    pub async fn changed_at_of(pool: PgPool, id: Uuid) -> anyhow::Result<Option<DateTime<Utc>>> {
+        let changed_at = sqlx::query_scalar!("SELECT changed_at FROM changes WHERE id = $1", id)
+            .fetch_optional(&pool)
+            .await?;
+        Ok(changed_at.map(|changed_at| changed_at.and_utc()))
+    }
+
+    /// The `(changed_at, id)` of the newest persisted change, if there are any.
+    pub async fn latest_key(pool: PgPool) -> anyhow::Result<Option<(DateTime<Utc>, Uuid)>> {
+        let latest = sqlx::query!(
+            "SELECT changed_at, id FROM changes ORDER BY changed_at DESC, id DESC LIMIT 1"
+        )
+        .fetch_optional(&pool)
+        .await?;
+        Ok(latest.map(|row| (row.changed_at.and_utc(), row.id)))
+    }
+
+    /// Fetches a page of persisted changes matching `filter` that come after `(changed_at, id)`,
+    /// in the order they're published in.
+    pub async fn fetch_after(
+        pool: PgPool,
+        changed_at: DateTime<Utc>,
+        id: Uuid,
+        filter: &ChangeFilter,
+        limit: i64,
+    ) -> anyhow::Result<Vec<ChangeEvent>> {
+        let results = sqlx::query(
+            r#"select
+  changes.id,
+  changes.trainno,
+  record_id,
+  changed_at,
+  field,
+  old_value,
+  new_value,
+  type,
+  coalesce(records.line, '') as line
+from
+    changes
+    left join records on records.id = changes.record_id
+where
+  (changed_at, changes.id) > ($1, $2)
+  and ($3::text is null or changes.trainno = $3)
+  and ($4::text is null or records.line = $4)
+  and ($5::text is null or field = $5)
+order by
+  changed_at, changes.id
+limit $6
+"#,
+        )
+        .bind(changed_at.naive_utc())
+        .bind(id)
+        .bind(&filter.trainno)
+        .bind(&filter.line)
+        .bind(&filter.field)
+        .bind(limit)
+        .fetch_all(&pool)
+        .await?;
+        Ok(results
+            .iter()
+            .map(|row| ChangeEvent {
+                change: Changed::from_row(row),
+                line: row.get("line"),
+            })
+            .collect())
+    }
+}
+
 pub struct Tracking<T> {
     pub most_recent_timestamp: DateTime<Utc>,
     pub most_recent_item: Option<Arc<T>>,
@@ -96,6 +299,84 @@ impl Fetch {
         .await?;
         Ok(())
     }
+
+    /// The most recent fetch with one of `statuses`, if any.
+    pub async fn last_with_status(
+        pg_pool: &PgPool,
+        statuses: &[&str],
+    ) -> anyhow::Result<Option<DateTime<Utc>>> {
+        let statuses: Vec<String> = statuses.iter().map(|s| s.to_string()).collect();
+        let timestamp = sqlx::query_scalar!(
+            "SELECT max(timestamp) FROM fetches WHERE status = ANY($1)",
+            &statuses
+        )
+        .fetch_one(pg_pool)
+        .await?;
+        Ok(timestamp.map(|timestamp| timestamp.and_utc()))
+    }
+}
+
+/// Fetches stored in one hour, by status. Hours without any fetches are included, since a gap
+/// is what a stalled poller looks like.
+#[derive(Debug, Serialize)]
+pub struct HourlyFetches {
+    #[serde(serialize_with = "crate::serde_utils::serialize_date_time")]
+    pub hour: DateTime<Utc>,
+    pub total: i64,
+    pub ok: i64,
+    pub unchanged: i64,
+    pub error: i64,
+    /// Shares of `total`, null for an hour without fetches.
+    pub ok_rate: Option<f64>,
+    pub unchanged_rate: Option<f64>,
+    pub error_rate: Option<f64>,
+}
+
+impl HourlyFetches {
+    /// Every hour from the one containing `since` up to the current one, oldest first.
+    pub async fn since(pg_pool: &PgPool, since: DateTime<Utc>) -> anyhow::Result<Vec<Self>> {
+        let rows = sqlx::query!(
+            r#"select
+  hours.hour as "hour!",
+  count(fetches.id) as "total!",
+  count(fetches.id) filter (where fetches.status = 'OK') as "ok!",
+  count(fetches.id) filter (where fetches.status = 'UNCHANGED') as "unchanged!",
+  count(fetches.id) filter (where fetches.status = 'FETCH_ERROR') as "error!"
+from
+  generate_series(
+    date_trunc('hour', $1::timestamp),
+    date_trunc('hour', (now() at time zone 'utc')),
+    interval '1 hour'
+  ) as hours(hour)
+  left join fetches on fetches.timestamp >= hours.hour
+  and fetches.timestamp < hours.hour + interval '1 hour'
+group by
+  hours.hour
+order by
+  hours.hour
+"#,
+            since.naive_utc()
+        )
+        .fetch_all(pg_pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                let rate = |count: i64| (row.total > 0).then(|| count as f64 / row.total as f64);
+                HourlyFetches {
+                    hour: row.hour.and_utc(),
+                    total: row.total,
+                    ok: row.ok,
+                    unchanged: row.unchanged,
+                    error: row.error,
+                    ok_rate: rate(row.ok),
+                    unchanged_rate: rate(row.unchanged),
+                    error_rate: rate(row.error),
+                }
+            })
+            .collect())
+    }
 }
 
 #[derive(Debug)]
@@ -106,3 +387,62 @@ impl Display for FailedFetchError {
     }
 }
 impl std::error::Error for FailedFetchError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{DurationRound, TimeDelta};
+
+    #[sqlx::test]
+    async fn hourly_fetches_include_empty_hours(pg_pool: PgPool) {
+        let this_hour = Utc::now().duration_trunc(TimeDelta::hours(1)).unwrap();
+        let two_hours_ago = this_hour - TimeDelta::hours(2);
+        for (hour, status) in [
+            (two_hours_ago, "OK"),
+            (two_hours_ago, "OK"),
+            (two_hours_ago, "UNCHANGED"),
+            (two_hours_ago, "FETCH_ERROR"),
+            (this_hour, "UNCHANGED"),
+        ] {
+            let fetch = Fetch::new(hour + TimeDelta::minutes(1), status.to_string(), None);
+            fetch.store_fetch(pg_pool.clone()).await.unwrap();
+        }
+        // Before the window.
+        Fetch::new(
+            two_hours_ago - TimeDelta::minutes(1),
+            "OK".to_string(),
+            None,
+        )
+        .store_fetch(pg_pool.clone())
+        .await
+        .unwrap();
+
+        let hourly = HourlyFetches::since(&pg_pool, two_hours_ago + TimeDelta::minutes(30))
+            .await
+            .unwrap();
+        let counts: Vec<_> = hourly
+            .iter()
+            .map(|hour| (hour.hour, hour.total, hour.ok, hour.unchanged, hour.error))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![
+                (two_hours_ago, 4, 2, 1, 1),
+                (two_hours_ago + TimeDelta::hours(1), 0, 0, 0, 0),
+                (this_hour, 1, 0, 1, 0),
+            ]
+        );
+        let rates: Vec<_> = hourly
+            .iter()
+            .map(|hour| (hour.ok_rate, hour.unchanged_rate, hour.error_rate))
+            .collect();
+        assert_eq!(
+            rates,
+            vec![
+                (Some(0.5), Some(0.25), Some(0.25)),
+                (None, None, None),
+                (Some(0.0), Some(1.0), Some(0.0)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,2 @@
+pub mod realtime;
+pub mod schedule;
@@ -0,0 +1,427 @@
+//! GTFS-realtime output, with the messages of `gtfs-realtime.proto` we fill in written out by
+//! hand (same names, tags and proto2 presence) rather than generated, so the build doesn't need
+//! `protoc`. Fields we never set are left out, which decoders treat as absent.
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Serialize, Serializer};
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    gtfs::schedule::{ScheduledStop, Trip},
+    septa::{stations::STATIONS, train_view::TrainView},
+};
+
+pub const GTFS_REALTIME_VERSION: &str = "2.0";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+#[repr(i32)]
+pub enum Incrementality {
+    FullDataset = 0,
+    Differential = 1,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+#[repr(i32)]
+pub enum TripScheduleRelationship {
+    Scheduled = 0,
+    Added = 1,
+    Unscheduled = 2,
+    Canceled = 3,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+#[repr(i32)]
+pub enum VehicleStopStatus {
+    IncomingAt = 0,
+    StoppedAt = 1,
+    InTransitTo = 2,
+}
+
+/// The proto names of enum values, for the JSON debug output.
+trait ProtoName: TryFrom<i32> {
+    fn proto_name(self) -> &'static str;
+}
+
+impl ProtoName for Incrementality {
+    fn proto_name(self) -> &'static str {
+        match self {
+            Incrementality::FullDataset => "FULL_DATASET",
+            Incrementality::Differential => "DIFFERENTIAL",
+        }
+    }
+}
+
+impl ProtoName for TripScheduleRelationship {
+    fn proto_name(self) -> &'static str {
+        match self {
+            TripScheduleRelationship::Scheduled => "SCHEDULED",
+            TripScheduleRelationship::Added => "ADDED",
+            TripScheduleRelationship::Unscheduled => "UNSCHEDULED",
+            TripScheduleRelationship::Canceled => "CANCELED",
+        }
+    }
+}
+
+impl ProtoName for VehicleStopStatus {
+    fn proto_name(self) -> &'static str {
+        match self {
+            VehicleStopStatus::IncomingAt => "INCOMING_AT",
+            VehicleStopStatus::StoppedAt => "STOPPED_AT",
+            VehicleStopStatus::InTransitTo => "IN_TRANSIT_TO",
+        }
+    }
+}
+
+fn serialize_enum<E: ProtoName, S: Serializer>(
+    value: &Option<i32>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match value.map(E::try_from) {
+        Some(Ok(value)) => serializer.serialize_str(value.proto_name()),
+        Some(Err(_)) => serializer.serialize_some(value),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize)]
+pub struct FeedMessage {
+    #[prost(message, required, tag = "1")]
+    pub header: FeedHeader,
+    #[prost(message, repeated, tag = "2")]
+    pub entity: Vec<FeedEntity>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize)]
+pub struct FeedHeader {
+    #[prost(string, required, tag = "1")]
+    pub gtfs_realtime_version: String,
+    #[prost(enumeration = "Incrementality", optional, tag = "2")]
+    #[serde(serialize_with = "serialize_enum::<Incrementality, _>")]
+    pub incrementality: Option<i32>,
+    #[prost(uint64, optional, tag = "3")]
+    pub timestamp: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize)]
+pub struct FeedEntity {
+    #[prost(string, required, tag = "1")]
+    pub id: String,
+    #[prost(message, optional, tag = "3")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trip_update: Option<TripUpdate>,
+    #[prost(message, optional, tag = "4")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vehicle: Option<VehiclePosition>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize)]
+pub struct TripUpdate {
+    #[prost(message, required, tag = "1")]
+    pub trip: TripDescriptor,
+    #[prost(message, repeated, tag = "2")]
+    pub stop_time_update: Vec<StopTimeUpdate>,
+    #[prost(message, optional, tag = "3")]
+    pub vehicle: Option<VehicleDescriptor>,
+    #[prost(uint64, optional, tag = "4")]
+    pub timestamp: Option<u64>,
+    /// Seconds late for the trip as a whole, for consumers that don't match the stop.
+    #[prost(int32, optional, tag = "5")]
+    pub delay: Option<i32>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize)]
+pub struct StopTimeEvent {
+    #[prost(int32, optional, tag = "1")]
+    pub delay: Option<i32>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize)]
+pub struct StopTimeUpdate {
+    #[prost(uint32, optional, tag = "1")]
+    pub stop_sequence: Option<u32>,
+    #[prost(message, optional, tag = "2")]
+    pub arrival: Option<StopTimeEvent>,
+    #[prost(message, optional, tag = "3")]
+    pub departure: Option<StopTimeEvent>,
+    #[prost(string, optional, tag = "4")]
+    pub stop_id: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize)]
+pub struct TripDescriptor {
+    #[prost(string, optional, tag = "1")]
+    pub trip_id: Option<String>,
+    /// `YYYYMMDD`.
+    #[prost(string, optional, tag = "3")]
+    pub start_date: Option<String>,
+    #[prost(enumeration = "TripScheduleRelationship", optional, tag = "4")]
+    #[serde(serialize_with = "serialize_enum::<TripScheduleRelationship, _>")]
+    pub schedule_relationship: Option<i32>,
+    #[prost(string, optional, tag = "5")]
+    pub route_id: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize)]
+pub struct VehicleDescriptor {
+    #[prost(string, optional, tag = "1")]
+    pub id: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    pub label: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize)]
+pub struct Position {
+    #[prost(float, required, tag = "1")]
+    pub latitude: f32,
+    #[prost(float, required, tag = "2")]
+    pub longitude: f32,
+    #[prost(float, optional, tag = "3")]
+    pub bearing: Option<f32>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize)]
+pub struct VehiclePosition {
+    #[prost(message, optional, tag = "1")]
+    pub trip: Option<TripDescriptor>,
+    #[prost(message, optional, tag = "2")]
+    pub position: Option<Position>,
+    #[prost(uint32, optional, tag = "3")]
+    pub current_stop_sequence: Option<u32>,
+    #[prost(enumeration = "VehicleStopStatus", optional, tag = "4")]
+    #[serde(serialize_with = "serialize_enum::<VehicleStopStatus, _>")]
+    pub current_status: Option<i32>,
+    #[prost(uint64, optional, tag = "5")]
+    pub timestamp: Option<u64>,
+    #[prost(string, optional, tag = "7")]
+    pub stop_id: Option<String>,
+    #[prost(message, optional, tag = "8")]
+    pub vehicle: Option<VehicleDescriptor>,
+}
+
+fn header(now: DateTime<Utc>) -> FeedHeader {
+    FeedHeader {
+        gtfs_realtime_version: GTFS_REALTIME_VERSION.to_string(),
+        incrementality: Some(Incrementality::FullDataset as i32),
+        timestamp: Some(now.timestamp() as u64),
+    }
+}
+
+fn trip_descriptor(trip: &Trip, service_date: NaiveDate) -> TripDescriptor {
+    TripDescriptor {
+        trip_id: Some(trip.trip_id.clone()),
+        start_date: Some(service_date.format("%Y%m%d").to_string()),
+        schedule_relationship: Some(TripScheduleRelationship::Scheduled as i32),
+        route_id: Some(trip.route_id.clone()),
+    }
+}
+
+/// Train numbers are the only vehicle identity the feed gives us.
+fn vehicle_descriptor(train: &TrainView) -> VehicleDescriptor {
+    VehicleDescriptor {
+        id: Some(train.trainno.clone()),
+        label: Some(train.trainno.clone()),
+    }
+}
+
+fn station_id(id: &Option<String>, name: &str) -> Option<String> {
+    id.clone().or_else(|| STATIONS.station_id(name))
+}
+
+/// The scheduled stop a train's `nextstop` refers to, looking past its `currentstop` first so a
+/// station the trip calls at twice resolves to the right call.
+fn next_scheduled_stop<'a>(train: &TrainView, trip: &'a Trip) -> Option<&'a ScheduledStop> {
+    let next = station_id(&train.nextstop_id, &train.nextstop)?;
+    let current = station_id(&train.currentstop_id, &train.currentstop);
+    let from = current
+        .and_then(|current| {
+            trip.stops
+                .iter()
+                .position(|stop| stop.station_id.as_ref() == Some(&current))
+        })
+        .map_or(0, |i| i + 1);
+    let find = |stops: &'a [ScheduledStop]| {
+        stops
+            .iter()
+            .find(|stop| stop.station_id.as_ref() == Some(&next))
+    };
+    find(&trip.stops[from..]).or_else(|| find(&trip.stops))
+}
+
+/// A trip update for every train that's linked to a scheduled trip, carrying its reported `late`
+/// as the delay from its next stop on. Trains without a trip are left out, since a trip update
+/// can't describe them.
+pub fn trip_updates(
+    trains: &[Arc<TrainView>],
+    trips: &HashMap<String, Trip>,
+    service_date: NaiveDate,
+    now: DateTime<Utc>,
+) -> FeedMessage {
+    let entity = trains
+        .iter()
+        .filter_map(|train| {
+            let trip = trips.get(&train.trainno)?;
+            let delay = train.late * 60;
+            let stop_time_update = next_scheduled_stop(train, trip)
+                .map(|stop| StopTimeUpdate {
+                    stop_sequence: u32::try_from(stop.stop_sequence).ok(),
+                    arrival: Some(StopTimeEvent { delay: Some(delay) }),
+                    departure: Some(StopTimeEvent { delay: Some(delay) }),
+                    stop_id: Some(stop.stop_id.clone()),
+                })
+                .into_iter()
+                .collect();
+            Some(FeedEntity {
+                id: train.trainno.clone(),
+                trip_update: Some(TripUpdate {
+                    trip: trip_descriptor(trip, service_date),
+                    stop_time_update,
+                    vehicle: Some(vehicle_descriptor(train)),
+                    timestamp: Some(train.last_seen().timestamp() as u64),
+                    delay: Some(delay),
+                }),
+                vehicle: None,
+            })
+        })
+        .collect();
+    FeedMessage {
+        header: header(now),
+        entity,
+    }
+}
+
+/// A vehicle position for every train, with the trip it's running when it's linked to one. The
+/// position is left out for trains that haven't reported one.
+pub fn vehicle_positions(
+    trains: &[Arc<TrainView>],
+    trips: &HashMap<String, Trip>,
+    service_date: NaiveDate,
+    now: DateTime<Utc>,
+) -> FeedMessage {
+    let entity = trains
+        .iter()
+        .map(|train| {
+            let trip = trips.get(&train.trainno);
+            let next_stop = trip.and_then(|trip| next_scheduled_stop(train, trip));
+            // Trains at their last stop report it as both the current and next stop.
+            let current_status = if train.currentstop == train.nextstop {
+                VehicleStopStatus::StoppedAt
+            } else {
+                VehicleStopStatus::InTransitTo
+            };
+            FeedEntity {
+                id: train.trainno.clone(),
+                trip_update: None,
+                vehicle: Some(VehiclePosition {
+                    trip: trip.map(|trip| trip_descriptor(trip, service_date)),
+                    position: train
+                        .lat
+                        .zip(train.lon)
+                        .map(|(latitude, longitude)| Position {
+                            latitude,
+                            longitude,
+                            bearing: train.heading,
+                        }),
+                    current_stop_sequence: next_stop
+                        .and_then(|stop| u32::try_from(stop.stop_sequence).ok()),
+                    current_status: next_stop.map(|_| current_status as i32),
+                    timestamp: Some(train.last_seen().timestamp() as u64),
+                    stop_id: next_stop.map(|stop| stop.stop_id.clone()),
+                    vehicle: Some(vehicle_descriptor(train)),
+                }),
+            }
+        })
+        .collect();
+    FeedMessage {
+        header: header(now),
+        entity,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+
+    fn train(trainno: &str, late: i32) -> Arc<TrainView> {
+        let mut train: TrainView = serde_json::from_value(serde_json::json!({
+            "lat": "40.0075",
+            "lon": "-75.2909",
+            "heading": "270",
+            "trainno": trainno,
+            "service": "LOCAL",
+            "dest": "Thorndale",
+            "currentstop": "Ardmore",
+            "nextstop": "Haverford",
+            "line": "Paoli/Thorndale",
+            "consist": "815,816",
+            "late": late,
+            "SOURCE": "30th Street Station",
+        }))
+        .unwrap();
+        train.timestamp = DateTime::from_timestamp(1_792_200_000, 0).unwrap();
+        Arc::new(train)
+    }
+
+    fn trip() -> Trip {
+        let stop =
+            |stop_sequence, stop_id: &str, stop_name: &str, station_id: &str| ScheduledStop {
+                stop_sequence,
+                stop_id: stop_id.to_string(),
+                stop_name: stop_name.to_string(),
+                station_id: Some(station_id.to_string()),
+                arrival_at: None,
+                departure_at: None,
+            };
+        Trip {
+            trip_id: "PAO_1001_V5_M".to_string(),
+            route_id: "PAO".to_string(),
+            trip_headsign: Some("Thorndale".to_string()),
+            stops: vec![
+                stop(1, "90511", "Ardmore", "ardmore"),
+                stop(2, "90510", "Haverford", "haverford"),
+            ],
+        }
+    }
+
+    #[test]
+    fn feeds_round_trip() {
+        let now = DateTime::from_timestamp(1_792_200_030, 0).unwrap();
+        let service_date = NaiveDate::from_ymd_opt(2026, 10, 18).unwrap();
+        let trips = HashMap::from([("1001".to_string(), trip())]);
+        let updates = trip_updates(&[train("1001", 3)], &trips, service_date, now);
+        let positions = vehicle_positions(&[train("2002", 0)], &trips, service_date, now);
+        let feed = FeedMessage {
+            header: updates.header,
+            entity: updates.entity.into_iter().chain(positions.entity).collect(),
+        };
+
+        let decoded = FeedMessage::decode(feed.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(decoded, feed);
+        assert_eq!(decoded.header.gtfs_realtime_version, "2.0");
+        assert_eq!(
+            decoded.header.incrementality,
+            Some(Incrementality::FullDataset as i32)
+        );
+        assert_eq!(decoded.header.timestamp, Some(1_792_200_030));
+        let ids: Vec<&str> = decoded.entity.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(ids, vec!["1001", "2002"]);
+
+        let update = decoded.entity[0].trip_update.as_ref().unwrap();
+        assert_eq!(update.delay, Some(180));
+        assert_eq!(update.trip.trip_id.as_deref(), Some("PAO_1001_V5_M"));
+        assert_eq!(update.trip.start_date.as_deref(), Some("20261018"));
+        let [stop_time] = update.stop_time_update.as_slice() else {
+            panic!("expected one stop time update");
+        };
+        assert_eq!(stop_time.stop_id.as_deref(), Some("90510"));
+        assert_eq!(stop_time.arrival.as_ref().unwrap().delay, Some(180));
+
+        let vehicle = decoded.entity[1].vehicle.as_ref().unwrap();
+        assert!(vehicle.trip.is_none());
+        let position = vehicle.position.as_ref().unwrap();
+        assert_eq!(
+            (position.latitude, position.longitude, position.bearing),
+            (40.0075, -75.2909, Some(270.0))
+        );
+    }
+}
@@ -0,0 +1,530 @@
+use chrono::{DateTime, Local, NaiveDate, TimeZone, Utc};
+use serde::{Deserialize, Deserializer, Serialize, de::DeserializeOwned};
+use sqlx::PgPool;
+use std::{collections::HashMap, fs::File, io::Read, path::Path};
+use zip::ZipArchive;
+
+use crate::septa::stations::Catalogue;
+
+/// Rows per insert statement, keeping the widest table well under Postgres' 65535 binds.
+const INSERT_CHUNK_ROWS: usize = 5000;
+
+#[derive(Debug, Deserialize)]
+struct Stop {
+    stop_id: String,
+    stop_name: String,
+    #[serde(default)]
+    stop_lat: Option<f32>,
+    #[serde(default)]
+    stop_lon: Option<f32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TripRow {
+    route_id: String,
+    service_id: String,
+    trip_id: String,
+    #[serde(default)]
+    trip_short_name: Option<String>,
+    #[serde(default)]
+    trip_headsign: Option<String>,
+    #[serde(default)]
+    direction_id: Option<i16>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StopTime {
+    trip_id: String,
+    #[serde(default, deserialize_with = "deserialize_opt_time")]
+    arrival_time: Option<i32>,
+    #[serde(default, deserialize_with = "deserialize_opt_time")]
+    departure_time: Option<i32>,
+    stop_id: String,
+    stop_sequence: i32,
+}
+
+#[derive(Debug, Deserialize)]
+struct Calendar {
+    service_id: String,
+    #[serde(deserialize_with = "deserialize_flag")]
+    monday: bool,
+    #[serde(deserialize_with = "deserialize_flag")]
+    tuesday: bool,
+    #[serde(deserialize_with = "deserialize_flag")]
+    wednesday: bool,
+    #[serde(deserialize_with = "deserialize_flag")]
+    thursday: bool,
+    #[serde(deserialize_with = "deserialize_flag")]
+    friday: bool,
+    #[serde(deserialize_with = "deserialize_flag")]
+    saturday: bool,
+    #[serde(deserialize_with = "deserialize_flag")]
+    sunday: bool,
+    #[serde(deserialize_with = "deserialize_gtfs_date")]
+    start_date: NaiveDate,
+    #[serde(deserialize_with = "deserialize_gtfs_date")]
+    end_date: NaiveDate,
+}
+
+#[derive(Debug, Deserialize)]
+struct CalendarDate {
+    service_id: String,
+    #[serde(deserialize_with = "deserialize_gtfs_date")]
+    date: NaiveDate,
+    exception_type: i16,
+}
+
+fn deserialize_flag<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    Ok(u8::deserialize(deserializer)? == 1)
+}
+
+/// `YYYYMMDD`.
+fn deserialize_gtfs_date<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<NaiveDate, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    NaiveDate::parse_from_str(&s, "%Y%m%d").map_err(serde::de::Error::custom)
+}
+
+/// `H:MM:SS` as seconds, which can be past `24:00:00` for trips running after midnight. Empty
+/// for stops without a scheduled time.
+fn deserialize_opt_time<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<i32>, D::Error> {
+    let Some(s) = Option::<String>::deserialize(deserializer)?.filter(|s| !s.trim().is_empty())
+    else {
+        return Ok(None);
+    };
+    let parts: Vec<&str> = s.trim().split(':').collect();
+    match parts[..] {
+        [h, m, sec] => match (h.parse::<i32>(), m.parse::<i32>(), sec.parse::<i32>()) {
+            (Ok(h), Ok(m), Ok(sec)) => Ok(Some(h * 3600 + m * 60 + sec)),
+            _ => Err(serde::de::Error::custom(format!("invalid time {s:?}"))),
+        },
+        _ => Err(serde::de::Error::custom(format!("invalid time {s:?}"))),
+    }
+}
+
+/// The tables of a GTFS static feed we keep.
+struct Feed {
+    stops: Vec<Stop>,
+    trips: Vec<TripRow>,
+    stop_times: Vec<StopTime>,
+    calendar: Vec<Calendar>,
+    calendar_dates: Vec<CalendarDate>,
+}
+
+fn read_table<T: DeserializeOwned>(
+    archive: &mut ZipArchive<File>,
+    name: &str,
+    required: bool,
+) -> anyhow::Result<Vec<T>> {
+    let mut contents = vec![];
+    match archive.by_name(name) {
+        Ok(mut file) => file.read_to_end(&mut contents)?,
+        Err(zip::result::ZipError::FileNotFound) if !required => return Ok(vec![]),
+        Err(e) => return Err(anyhow::anyhow!("Error reading {name}: {e}")),
+    };
+    csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(contents.as_slice())
+        .deserialize()
+        .enumerate()
+        .map(|(i, row)| row.map_err(|e| anyhow::anyhow!("{name} row {}: {e}", i + 1)))
+        .collect()
+}
+
+impl Feed {
+    fn read_zip(path: &Path) -> anyhow::Result<Feed> {
+        let mut archive = ZipArchive::new(File::open(path)?)?;
+        let feed = Feed {
+            stops: read_table(&mut archive, "stops.txt", true)?,
+            trips: read_table(&mut archive, "trips.txt", true)?,
+            stop_times: read_table(&mut archive, "stop_times.txt", true)?,
+            calendar: read_table(&mut archive, "calendar.txt", false)?,
+            calendar_dates: read_table(&mut archive, "calendar_dates.txt", false)?,
+        };
+        if feed.calendar.is_empty() && feed.calendar_dates.is_empty() {
+            anyhow::bail!("Feed has neither calendar.txt nor calendar_dates.txt");
+        }
+        Ok(feed)
+    }
+}
+
+#[derive(Debug)]
+pub struct ImportSummary {
+    pub stops: usize,
+    /// Stops whose name matched a station in the catalogue.
+    pub matched_stops: usize,
+    pub trips: usize,
+    pub stop_times: usize,
+    pub services: usize,
+}
+
+/// Replaces the imported schedule with the GTFS static feed zipped at `path`, matching its stops
+/// to stations in `catalogue` by name.
+pub async fn import(
+    pool: &PgPool,
+    path: &Path,
+    catalogue: &Catalogue,
+) -> anyhow::Result<ImportSummary> {
+    let feed = tokio::task::spawn_blocking({
+        let path = path.to_owned();
+        move || Feed::read_zip(&path)
+    })
+    .await??;
+    let station_ids: Vec<Option<String>> = feed
+        .stops
+        .iter()
+        .map(|stop| {
+            let id = catalogue.station_id(&stop.stop_name);
+            if id.is_none() {
+                warn!(
+                    "No station matches GTFS stop {} {:?}.",
+                    stop.stop_id, stop.stop_name
+                );
+            }
+            id
+        })
+        .collect();
+
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+        "truncate gtfs_stops, gtfs_trips, gtfs_stop_times, gtfs_calendar, gtfs_calendar_dates",
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let stops: Vec<(&Stop, &Option<String>)> = feed.stops.iter().zip(&station_ids).collect();
+    for chunk in stops.chunks(INSERT_CHUNK_ROWS) {
+        let mut builder = sqlx::QueryBuilder::new(
+            "insert into gtfs_stops (stop_id, stop_name, lat, lon, station_id) ",
+        );
+        builder.push_values(chunk, |mut b, (stop, station_id)| {
+            b.push_bind(&stop.stop_id)
+                .push_bind(&stop.stop_name)
+                .push_bind(stop.stop_lat)
+                .push_bind(stop.stop_lon)
+                .push_bind(*station_id);
+        });
+        builder.build().execute(&mut *tx).await?;
+    }
+    for chunk in feed.trips.chunks(INSERT_CHUNK_ROWS) {
+        let mut builder = sqlx::QueryBuilder::new(
+            "insert into gtfs_trips (trip_id, route_id, service_id, trip_short_name, trip_headsign, direction_id) ",
+        );
+        builder.push_values(chunk, |mut b, trip| {
+            b.push_bind(&trip.trip_id)
+                .push_bind(&trip.route_id)
+                .push_bind(&trip.service_id)
+                .push_bind(&trip.trip_short_name)
+                .push_bind(&trip.trip_headsign)
+                .push_bind(trip.direction_id);
+        });
+        builder.build().execute(&mut *tx).await?;
+    }
+    for chunk in feed.stop_times.chunks(INSERT_CHUNK_ROWS) {
+        let mut builder = sqlx::QueryBuilder::new(
+            "insert into gtfs_stop_times (trip_id, stop_sequence, stop_id, arrival_secs, departure_secs) ",
+        );
+        builder.push_values(chunk, |mut b, stop_time| {
+            b.push_bind(&stop_time.trip_id)
+                .push_bind(stop_time.stop_sequence)
+                .push_bind(&stop_time.stop_id)
+                .push_bind(stop_time.arrival_time)
+                .push_bind(stop_time.departure_time);
+        });
+        builder.build().execute(&mut *tx).await?;
+    }
+    for chunk in feed.calendar.chunks(INSERT_CHUNK_ROWS) {
+        let mut builder = sqlx::QueryBuilder::new(
+            "insert into gtfs_calendar (service_id, monday, tuesday, wednesday, thursday, friday, saturday, sunday, start_date, end_date) ",
+        );
+        builder.push_values(chunk, |mut b, calendar| {
+            b.push_bind(&calendar.service_id)
+                .push_bind(calendar.monday)
+                .push_bind(calendar.tuesday)
+                .push_bind(calendar.wednesday)
+                .push_bind(calendar.thursday)
+                .push_bind(calendar.friday)
+                .push_bind(calendar.saturday)
+                .push_bind(calendar.sunday)
+                .push_bind(calendar.start_date)
+                .push_bind(calendar.end_date);
+        });
+        builder.build().execute(&mut *tx).await?;
+    }
+    for chunk in feed.calendar_dates.chunks(INSERT_CHUNK_ROWS) {
+        let mut builder = sqlx::QueryBuilder::new(
+            "insert into gtfs_calendar_dates (service_id, date, exception_type) ",
+        );
+        builder.push_values(chunk, |mut b, date| {
+            b.push_bind(&date.service_id)
+                .push_bind(date.date)
+                .push_bind(date.exception_type);
+        });
+        builder.build().execute(&mut *tx).await?;
+    }
+    tx.commit().await?;
+
+    let mut services: Vec<&str> = feed
+        .calendar
+        .iter()
+        .map(|c| c.service_id.as_str())
+        .chain(feed.calendar_dates.iter().map(|d| d.service_id.as_str()))
+        .collect();
+    services.sort_unstable();
+    services.dedup();
+    Ok(ImportSummary {
+        stops: feed.stops.len(),
+        matched_stops: station_ids.iter().filter(|id| id.is_some()).count(),
+        trips: feed.trips.len(),
+        stop_times: feed.stop_times.len(),
+        services: services.len(),
+    })
+}
+
+/// The instant a GTFS time on `service_date` refers to. GTFS times count from noon minus 12
+/// hours rather than midnight, which only differs on days the clocks change.
+pub fn service_time(service_date: NaiveDate, secs: i32) -> DateTime<Utc> {
+    let noon = service_date.and_hms_opt(12, 0, 0).unwrap();
+    let noon = Local
+        .from_local_datetime(&noon)
+        .earliest()
+        .unwrap()
+        .to_utc();
+    noon + chrono::Duration::seconds(secs as i64 - 12 * 3600)
+}
+
+/// A stop of a scheduled trip, with its times on the trip's service date.
+#[derive(Debug, Serialize, Clone)]
+pub struct ScheduledStop {
+    pub stop_sequence: i32,
+    pub stop_id: String,
+    pub stop_name: String,
+    pub station_id: Option<String>,
+    #[serde(serialize_with = "crate::serde_utils::serialize_opt_date_time")]
+    pub arrival_at: Option<DateTime<Utc>>,
+    #[serde(serialize_with = "crate::serde_utils::serialize_opt_date_time")]
+    pub departure_at: Option<DateTime<Utc>>,
+}
+
+/// The GTFS trip a train number ran as on a service day.
+#[derive(Debug, Serialize, Clone)]
+pub struct Trip {
+    pub trip_id: String,
+    pub route_id: String,
+    pub trip_headsign: Option<String>,
+    pub stops: Vec<ScheduledStop>,
+}
+
+impl Trip {
+    /// The trips scheduled on `service_date` for each of `trainnos`, keyed by train number, which
+    /// is the trip's `trip_short_name`. Train numbers without a trip that day are left out.
+    pub async fn for_trains(
+        pool: &PgPool,
+        service_date: NaiveDate,
+        trainnos: &[String],
+    ) -> anyhow::Result<HashMap<String, Trip>> {
+        let rows = sqlx::query!(
+            r#"with active as (
+  select service_id from gtfs_calendar
+  where
+    $1 between start_date and end_date
+    and (array[monday, tuesday, wednesday, thursday, friday, saturday, sunday])[extract(isodow from $1::date)::int]
+  union
+  select service_id from gtfs_calendar_dates where date = $1 and exception_type = 1
+  except
+  select service_id from gtfs_calendar_dates where date = $1 and exception_type = 2
+)
+select
+  distinct on (trip_short_name)
+  trip_id,
+  route_id,
+  trip_short_name as "trip_short_name!",
+  trip_headsign
+from
+    gtfs_trips
+    join active using (service_id)
+where
+  trip_short_name = any($2)
+order by
+  trip_short_name, trip_id
+"#,
+            service_date,
+            trainnos
+        )
+        .fetch_all(pool)
+        .await?;
+
+        let mut trips: HashMap<String, Trip> = rows
+            .into_iter()
+            .map(|row| {
+                (
+                    row.trip_short_name,
+                    Trip {
+                        trip_id: row.trip_id,
+                        route_id: row.route_id,
+                        trip_headsign: row.trip_headsign,
+                        stops: vec![],
+                    },
+                )
+            })
+            .collect();
+        let trip_ids: Vec<String> = trips.values().map(|trip| trip.trip_id.clone()).collect();
+        let mut by_trip_id: HashMap<String, &mut Trip> = trips
+            .values_mut()
+            .map(|trip| (trip.trip_id.clone(), trip))
+            .collect();
+
+        let stops = sqlx::query!(
+            r#"select
+  gtfs_stop_times.trip_id,
+  gtfs_stop_times.stop_sequence,
+  gtfs_stop_times.stop_id,
+  gtfs_stops.stop_name,
+  gtfs_stops.station_id,
+  gtfs_stop_times.arrival_secs,
+  gtfs_stop_times.departure_secs
+from
+    gtfs_stop_times
+    join gtfs_stops on gtfs_stops.stop_id = gtfs_stop_times.stop_id
+where
+  gtfs_stop_times.trip_id = any($1)
+order by
+  gtfs_stop_times.trip_id, gtfs_stop_times.stop_sequence
+"#,
+            &trip_ids
+        )
+        .fetch_all(pool)
+        .await?;
+        for stop in stops {
+            if let Some(trip) = by_trip_id.get_mut(&stop.trip_id) {
+                trip.stops.push(ScheduledStop {
+                    stop_sequence: stop.stop_sequence,
+                    stop_id: stop.stop_id,
+                    stop_name: stop.stop_name,
+                    station_id: stop.station_id,
+                    arrival_at: stop
+                        .arrival_secs
+                        .map(|secs| service_time(service_date, secs)),
+                    departure_at: stop
+                        .departure_secs
+                        .map(|secs| service_time(service_date, secs)),
+                });
+            }
+        }
+        Ok(trips)
+    }
+
+    /// The trip `trainno` ran as on `service_date`, if the imported schedule has one.
+    pub async fn for_train(
+        pool: &PgPool,
+        service_date: NaiveDate,
+        trainno: &str,
+    ) -> anyhow::Result<Option<Trip>> {
+        Ok(Trip::for_trains(pool, service_date, &[trainno.to_string()])
+            .await?
+            .remove(trainno))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveTime;
+
+    /// Reads rows the way `read_table` does.
+    fn rows<T: DeserializeOwned>(csv: &str) -> Result<Vec<T>, csv::Error> {
+        csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(csv.as_bytes())
+            .deserialize()
+            .collect()
+    }
+
+    #[test]
+    fn stop_times_past_midnight_and_without_times() {
+        let stop_times: Vec<StopTime> = rows(
+            "trip_id,arrival_time,departure_time,stop_id,stop_sequence
+PAO_1001,6:05:00,6:05:30,90511,1
+PAO_1001,,,90510,2
+PAO_1001,25:10:00,25:10:00,90509,3",
+        )
+        .unwrap();
+        let times: Vec<(Option<i32>, Option<i32>)> = stop_times
+            .iter()
+            .map(|stop| (stop.arrival_time, stop.departure_time))
+            .collect();
+        assert_eq!(
+            times,
+            vec![
+                (Some(6 * 3600 + 5 * 60), Some(6 * 3600 + 5 * 60 + 30)),
+                (None, None),
+                (Some(25 * 3600 + 10 * 60), Some(25 * 3600 + 10 * 60)),
+            ]
+        );
+        assert!(
+            rows::<StopTime>(
+                "trip_id,arrival_time,departure_time,stop_id,stop_sequence\nA,6:05,,1,1"
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn times_past_24_hours_fall_on_the_next_day() {
+        let date = NaiveDate::from_ymd_opt(2026, 10, 14).unwrap();
+        let local = |date: NaiveDate, h, m| {
+            Local
+                .from_local_datetime(&date.and_time(NaiveTime::from_hms_opt(h, m, 0).unwrap()))
+                .unwrap()
+                .to_utc()
+        };
+        assert_eq!(service_time(date, 6 * 3600 + 5 * 60), local(date, 6, 5));
+        assert_eq!(
+            service_time(date, 25 * 3600 + 10 * 60),
+            local(date.succ_opt().unwrap(), 1, 10)
+        );
+    }
+
+    #[test]
+    fn calendar_flags_and_dates() {
+        let calendar: Vec<Calendar> = rows(
+            "service_id,monday,tuesday,wednesday,thursday,friday,saturday,sunday,start_date,end_date
+M1,1,1,1,1,1,0,0,20260901,20261231",
+        )
+        .unwrap();
+        let [weekdays] = calendar.as_slice() else {
+            panic!("expected one service");
+        };
+        assert_eq!(
+            [
+                weekdays.monday,
+                weekdays.tuesday,
+                weekdays.wednesday,
+                weekdays.thursday,
+                weekdays.friday,
+                weekdays.saturday,
+                weekdays.sunday
+            ],
+            [true, true, true, true, true, false, false]
+        );
+        assert_eq!(
+            weekdays.start_date,
+            NaiveDate::from_ymd_opt(2026, 9, 1).unwrap()
+        );
+        assert_eq!(
+            weekdays.end_date,
+            NaiveDate::from_ymd_opt(2026, 12, 31).unwrap()
+        );
+
+        let dates: Vec<CalendarDate> =
+            rows("service_id,date,exception_type\nM1,20261126,2").unwrap();
+        assert_eq!(
+            dates[0].date,
+            NaiveDate::from_ymd_opt(2026, 11, 26).unwrap()
+        );
+        assert!(rows::<CalendarDate>("service_id,date,exception_type\nM1,2026-11-26,2").is_err());
+    }
+}
@@ -2,13 +2,27 @@
 extern crate log;
 
 use actix_web::{App, HttpServer};
+use anyhow::{anyhow, bail};
+use chrono::NaiveDate;
 use sqlx::PgPool;
-use std::{collections::HashMap, env, sync::Arc};
-use tokio::sync::RwLock;
+use std::{collections::HashMap, env, sync::Arc, time::Duration};
+use tokio::sync::{RwLock, broadcast};
 
-use crate::{db::tracking::Tracking, septa::train_view::TrainView};
+use crate::{
+    config::Config,
+    db::tracking::{ChangeEvent, Tracking},
+    septa::{
+        backoff::{BackoffConfig, CircuitBreaker},
+        stations::{self, STATIONS, Station},
+        train_view::{TrainUpdate, TrainView},
+    },
+};
 
+mod analytics;
+mod config;
 mod db;
+mod gtfs;
+mod metrics;
 mod septa;
 mod serde_utils;
 mod web;
@@ -16,11 +30,28 @@ mod web;
 struct AppState {
     train_statuses: HashMap<String, Tracking<TrainView>>,
     pg_pool: PgPool,
+    circuit_breaker: CircuitBreaker,
+    config: Arc<Config>,
+    change_sender: broadcast::Sender<ChangeEvent>,
+    train_sender: broadcast::Sender<Arc<TrainUpdate>>,
 }
 type SharedAppState = Arc<RwLock<AppState>>;
 
+/// How many changes a slow live subscriber can fall behind by before it starts missing them.
+const CHANGE_CHANNEL_CAPACITY: usize = 1024;
+/// Every train is published on each poll, so this covers a few polls' worth of updates.
+const TRAIN_CHANNEL_CAPACITY: usize = 1024;
+
 async fn populate_known_statuses(state: SharedAppState) -> anyhow::Result<usize> {
-    let train_views = TrainView::get_most_recent_all(state.read().await.pg_pool.clone()).await?;
+    let (pg_pool, since) = {
+        let state = state.read().await;
+        let yesterday = chrono::Local::now() - chrono::Duration::days(1);
+        (
+            state.pg_pool.clone(),
+            state.config.septa.service_day_start(yesterday),
+        )
+    };
+    let train_views = TrainView::get_most_recent_all(pg_pool, since).await?;
     let train_statuses = &mut state.write().await.train_statuses;
     train_views.iter().for_each(|train_view| {
         train_statuses.insert(
@@ -35,23 +66,127 @@ async fn populate_known_statuses(state: SharedAppState) -> anyhow::Result<usize>
     Ok(train_views.len())
 }
 
+/// `export-parquet FROM [TO]`: writes a Parquet partition per service day in `[FROM, TO]`
+/// (dates as `YYYY-MM-DD`, `TO` defaulting to `FROM`) to `files.parquet_dir`, then exits.
+async fn export_parquet(config: &Config, args: &[String]) -> anyhow::Result<()> {
+    let parse = |arg: &String| {
+        NaiveDate::parse_from_str(arg, "%Y-%m-%d").map_err(|e| anyhow!("Invalid date {arg:?}: {e}"))
+    };
+    let (from, to) = match args {
+        [from] => (parse(from)?, parse(from)?),
+        [from, to] => (parse(from)?, parse(to)?),
+        _ => bail!("Usage: septa export-parquet FROM [TO]"),
+    };
+    if from > to {
+        bail!("FROM must not be after TO");
+    }
+
+    let pool = db::init().await?;
+    let dir = std::path::Path::new(&config.files.parquet_dir);
+    for day in analytics::parquet::export_range(&pool, &config.septa, from, to, dir).await? {
+        println!(
+            "{}: {} records, {} changes",
+            day.service_date, day.records, day.changes
+        );
+    }
+    Ok(())
+}
+
+/// `normalise-stations`: sets the station ids of every record from its stop names, for records
+/// stored before the names were in the catalogue, then exits.
+async fn normalise_stations() -> anyhow::Result<()> {
+    let pool = db::init().await?;
+    Station::seed(&pool, &STATIONS).await?;
+    let updated = stations::normalise_records(&pool, &STATIONS).await?;
+    println!("Updated {updated} station ids");
+    Ok(())
+}
+
+/// `import-gtfs PATH`: replaces the imported schedule with the GTFS static feed zipped at
+/// `PATH`, then exits.
+async fn import_gtfs(args: &[String]) -> anyhow::Result<()> {
+    let [path] = args else {
+        bail!("Usage: septa import-gtfs PATH");
+    };
+    let pool = db::init().await?;
+    let summary = gtfs::schedule::import(&pool, std::path::Path::new(path), &STATIONS).await?;
+    println!(
+        "Imported {} stops ({} matched to stations), {} trips, {} stop times and {} services",
+        summary.stops, summary.matched_stops, summary.trips, summary.stop_times, summary.services
+    );
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenvy::dotenv().unwrap();
     pretty_env_logger::init_timed();
     let fetch_and_process_septa = !env::var("NO_FETCH").map(|v| v == "true").unwrap_or(false);
+    let replay_dir = env::var("REPLAY_DIR").ok().filter(|v| !v.is_empty());
+    let source_dir = env::var("TRAIN_SOURCE_DIR").ok().filter(|v| !v.is_empty());
+
+    let config = Arc::new(Config::load()?);
+    debug!("Loaded config: {config:?}");
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("export-parquet") {
+        return export_parquet(&config, &args[1..]).await;
+    }
+    if args.first().map(String::as_str) == Some("normalise-stations") {
+        return normalise_stations().await;
+    }
+    if args.first().map(String::as_str) == Some("import-gtfs") {
+        return import_gtfs(&args[1..]).await;
+    }
+
+    let pg_pool = db::init().await.unwrap();
+    let seeded = Station::seed(&pg_pool, &STATIONS).await?;
+    info!("Seeded {} stations.", seeded);
 
     let state = AppState {
         train_statuses: HashMap::new(),
-        pg_pool: db::init().await.unwrap(),
+        pg_pool,
+        circuit_breaker: CircuitBreaker::new(BackoffConfig::from(&config.septa)),
+        config: config.clone(),
+        change_sender: broadcast::channel(CHANGE_CHANNEL_CAPACITY).0,
+        train_sender: broadcast::channel(TRAIN_CHANNEL_CAPACITY).0,
     };
     let state = Arc::new(RwLock::new(state));
-    let backfilled = populate_known_statuses(state.clone()).await?;
-    info!("Backfilled {} statuses during startup.", backfilled);
+    // Replays start from no known trains: the latest stored records are usually newer than the
+    // archived files, so every replayed file would be diffed against the future.
+    if replay_dir.is_none() {
+        let backfilled = populate_known_statuses(state.clone()).await?;
+        info!("Backfilled {} statuses during startup.", backfilled);
+    }
 
-    if fetch_and_process_septa {
+    if let Some(replay_dir) = replay_dir {
+        info!("Replaying archived files from {replay_dir}");
+        match septa::processing::start_replay(state.clone(), replay_dir.into()).await {
+            Ok((replay_handle, process_handle)) => {
+                debug!("Started threads: {:?} {:?}", replay_handle, process_handle);
+            }
+            Err(e) => {
+                error!("Error starting replay threads: {e:?}");
+                return Err(e);
+            }
+        }
+    } else if fetch_and_process_septa {
         info!("Starting Septa processes");
-        match septa::processing::start(state.clone()).await {
+        let started = match source_dir {
+            Some(source_dir) => {
+                info!("Polling fixtures from {source_dir} instead of Septa");
+                let source = septa::api::FixtureSource::from_dir(&source_dir).await?;
+                septa::processing::start(state.clone(), source).await
+            }
+            None => {
+                let source = septa::api::SeptaApi::new(
+                    &config.septa.url,
+                    Duration::from_secs(config.septa.request_timeout_secs),
+                )?;
+                septa::processing::start(state.clone(), source).await
+            }
+        };
+        match started {
             Ok((poll_handle, process_handle)) => {
                 debug!("Started threads: {:?} {:?}", poll_handle, process_handle);
             }
@@ -65,9 +200,10 @@ async fn main() -> anyhow::Result<()> {
     let _ = HttpServer::new(move || {
         App::new()
             .app_data(actix_web::web::Data::new(state.clone()))
+            .wrap(actix_web::middleware::from_fn(web::record_metrics))
             .configure(web::routes)
     })
-    .bind(("0.0.0.0", 8081))
+    .bind((config.server.host.as_str(), config.server.port))
     .unwrap()
     .run()
     .await;
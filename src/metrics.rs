@@ -0,0 +1,104 @@
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder, core::Collector,
+};
+use std::sync::LazyLock;
+
+/// Everything exposed on `/metrics`.
+pub struct Metrics {
+    registry: Registry,
+    /// Fetches by the status stored for them in `fetches` (`OK`, `UNCHANGED`, `FETCH_ERROR`).
+    pub fetches: IntCounterVec,
+    pub fetch_duration: Histogram,
+    pub trains_processed: IntCounter,
+    pub trains_updated: IntCounter,
+    /// Trains that went through `accept_new_file` in the most recent cycle.
+    pub cycle_trains_processed: IntGauge,
+    pub cycle_trains_updated: IntGauge,
+    /// Payloads waiting in the channel between the poller and `accept_new_file`.
+    pub channel_depth: IntGauge,
+    pub db_insert_duration: Histogram,
+    pub http_requests: IntCounterVec,
+    pub http_request_duration: HistogramVec,
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Metrics> {
+        let registry = Registry::new();
+        let metrics = Metrics {
+            fetches: IntCounterVec::new(
+                Opts::new("septa_fetches_total", "TrainView fetches by status."),
+                &["status"],
+            )?,
+            fetch_duration: Histogram::with_opts(HistogramOpts::new(
+                "septa_fetch_duration_seconds",
+                "Time taken to fetch the TrainView, successful or not.",
+            ))?,
+            trains_processed: IntCounter::new(
+                "septa_trains_processed_total",
+                "Changed trains processed from fetched TrainViews.",
+            )?,
+            trains_updated: IntCounter::new(
+                "septa_trains_updated_total",
+                "Processed trains that updated an already known train.",
+            )?,
+            cycle_trains_processed: IntGauge::new(
+                "septa_cycle_trains_processed",
+                "Changed trains processed in the most recent cycle.",
+            )?,
+            cycle_trains_updated: IntGauge::new(
+                "septa_cycle_trains_updated",
+                "Known trains updated in the most recent cycle.",
+            )?,
+            channel_depth: IntGauge::new(
+                "septa_processing_channel_depth",
+                "TrainViews waiting to be processed.",
+            )?,
+            db_insert_duration: Histogram::with_opts(HistogramOpts::new(
+                "septa_db_insert_duration_seconds",
+                "Time taken to insert a file's new records.",
+            ))?,
+            http_requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "HTTP requests by route and status."),
+                &["method", "route", "status"],
+            )?,
+            http_request_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "http_request_duration_seconds",
+                    "Time taken to respond to HTTP requests, up to the response head for streams.",
+                ),
+                &["method", "route"],
+            )?,
+            registry,
+        };
+
+        let collectors: [Box<dyn Collector>; 10] = [
+            Box::new(metrics.fetches.clone()),
+            Box::new(metrics.fetch_duration.clone()),
+            Box::new(metrics.trains_processed.clone()),
+            Box::new(metrics.trains_updated.clone()),
+            Box::new(metrics.cycle_trains_processed.clone()),
+            Box::new(metrics.cycle_trains_updated.clone()),
+            Box::new(metrics.channel_depth.clone()),
+            Box::new(metrics.db_insert_duration.clone()),
+            Box::new(metrics.http_requests.clone()),
+            Box::new(metrics.http_request_duration.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector)?;
+        }
+        Ok(metrics)
+    }
+
+    /// Renders every metric in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut buffer = vec![];
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            error!("Error encoding metrics: {e}");
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+pub static METRICS: LazyLock<Metrics> =
+    LazyLock::new(|| Metrics::new().expect("Metric definitions are invalid"));
@@ -1,52 +1,119 @@
-use std::fmt::Debug;
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
 
 use chrono::{DateTime, Utc};
 use reqwest;
 
-use crate::{
-    db::tracking::FailedFetchError, septa::content::Content, septa::train_view::TrainView,
-};
+use crate::{db::tracking::FailedFetchError, septa::content::Content};
 fn err_to_string<E: Debug>(e: E) -> String {
     format!("{:?}", e)
 }
 
-pub async fn fetch_train_view() -> anyhow::Result<Content, FailedFetchError> {
-    let url = "https://www3.septa.org/api/TrainView/index.php";
-    let response = reqwest::get(url)
-        .await
-        .map_err(|e| FailedFetchError(chrono::Utc::now(), format!("{e:?}")))?;
-
-    info!("Fetched with status: {}", response.status());
-    trace!("Headers:\n{:#?}", response.headers());
-    let date: DateTime<Utc> = response
-        .headers()
-        .get("date")
-        .map(|hv| {
-            DateTime::parse_from_rfc2822(hv.to_str().unwrap())
-                .unwrap()
-                .to_utc()
+/// Somewhere the poller can get the current list of train views from.
+pub trait TrainSource: Send + Sync + 'static {
+    fn fetch_train_view(&self) -> impl Future<Output = Result<Content, FailedFetchError>> + Send;
+
+    /// Whether there's nothing left to fetch, which stops the poller.
+    fn is_exhausted(&self) -> bool {
+        false
+    }
+}
+
+/// The live Septa TrainView api.
+pub struct SeptaApi {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl SeptaApi {
+    pub fn new(url: impl Into<String>, timeout: Duration) -> reqwest::Result<Self> {
+        Ok(SeptaApi {
+            client: reqwest::Client::builder().timeout(timeout).build()?,
+            url: url.into(),
         })
-        .unwrap_or(chrono::Utc::now());
-
-    // match response.text().await {
-    //     Ok(raw) => match serde_json::from_str::<Vec<TrainView>>(&raw) {
-    //         Ok(body) => {}
-    //     },
-    // }
-    match response
-        .text()
-        .await
-        .map_err(err_to_string)
-        .and_then(|body| {
-            serde_json::from_str::<Vec<TrainView>>(&body)
-                .map(|v| (body, v))
-                .map_err(err_to_string)
-        }) {
-        Ok((raw, body)) => Ok(Content {
-            timestamp: date,
-            raw,
-            trains: body,
-        }),
-        Err(e) => Err(FailedFetchError(date, e).into()),
+    }
+}
+
+impl TrainSource for SeptaApi {
+    async fn fetch_train_view(&self) -> Result<Content, FailedFetchError> {
+        let response = self
+            .client
+            .get(&self.url)
+            .send()
+            .await
+            .map_err(|e| FailedFetchError(chrono::Utc::now(), format!("{e:?}")))?;
+
+        info!("Fetched with status: {}", response.status());
+        trace!("Headers:\n{:#?}", response.headers());
+        let date: DateTime<Utc> = response
+            .headers()
+            .get("date")
+            .map(|hv| {
+                DateTime::parse_from_rfc2822(hv.to_str().unwrap())
+                    .unwrap()
+                    .to_utc()
+            })
+            .unwrap_or(chrono::Utc::now());
+
+        response
+            .text()
+            .await
+            .map_err(err_to_string)
+            .and_then(|body| Content::from_raw(date, body).map_err(err_to_string))
+            .map_err(|e| FailedFetchError(date, e))
+    }
+}
+
+/// Serves saved TrainView payloads one per fetch, stamped with the time they're fetched at.
+/// Once every fixture has been served it's exhausted, so the poller stops.
+pub struct FixtureSource {
+    fixtures: Mutex<VecDeque<PathBuf>>,
+}
+
+impl FixtureSource {
+    pub fn from_files(files: Vec<PathBuf>) -> Self {
+        FixtureSource {
+            fixtures: Mutex::new(files.into()),
+        }
+    }
+
+    /// Loads every `.json` file in `dir`, served in file name order.
+    pub async fn from_dir(dir: impl AsRef<Path>) -> std::io::Result<Self> {
+        let mut files = vec![];
+        let mut entries = tokio::fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().and_then(|ext| ext.to_str()) == Some("json") {
+                files.push(path);
+            }
+        }
+        files.sort();
+        Ok(FixtureSource::from_files(files))
+    }
+}
+
+impl TrainSource for FixtureSource {
+    async fn fetch_train_view(&self) -> Result<Content, FailedFetchError> {
+        let next = self.fixtures.lock().unwrap().pop_front();
+        let date = chrono::Utc::now();
+        let Some(path) = next else {
+            return Err(FailedFetchError(date, "No fixtures remaining".to_string()));
+        };
+        info!("Serving fixture: {:?}", path);
+
+        tokio::fs::read_to_string(&path)
+            .await
+            .map_err(err_to_string)
+            .and_then(|body| Content::from_raw(date, body).map_err(err_to_string))
+            .map_err(|e| FailedFetchError(date, e))
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.fixtures.lock().unwrap().is_empty()
     }
 }
@@ -0,0 +1,182 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::time::Duration;
+
+use crate::{config::SeptaConfig, db::tracking::FailedFetchError};
+
+#[derive(Debug, Clone)]
+pub struct BackoffConfig {
+    /// Time between polls while fetches are succeeding.
+    pub interval: Duration,
+    /// Upper bound for the exponential backoff between failed fetches.
+    pub max_backoff: Duration,
+    /// Consecutive failures before the breaker opens.
+    pub breaker_threshold: u32,
+    /// How long an open breaker waits before letting a trial fetch through.
+    pub breaker_cooldown: Duration,
+}
+
+impl From<&SeptaConfig> for BackoffConfig {
+    fn from(config: &SeptaConfig) -> Self {
+        BackoffConfig {
+            interval: Duration::from_secs(config.poll_interval_secs),
+            max_backoff: Duration::from_secs(config.max_backoff_secs),
+            breaker_threshold: config.breaker_threshold,
+            breaker_cooldown: Duration::from_secs(config.breaker_cooldown_secs),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Fetches are going through on the normal interval (or backing off).
+    #[default]
+    Closed,
+    /// Too many consecutive failures, waiting out the cooldown.
+    Open,
+    /// Cooldown elapsed, the next fetch decides whether to close or reopen.
+    HalfOpen,
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    config: BackoffConfig,
+    pub state: CircuitState,
+    pub consecutive_failures: u32,
+    pub last_failure_at: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BackoffConfig) -> Self {
+        CircuitBreaker {
+            config,
+            state: CircuitState::Closed,
+            consecutive_failures: 0,
+            last_failure_at: None,
+            last_error: None,
+        }
+    }
+
+    /// Called right before a fetch. An open breaker has already waited its cooldown by the time
+    /// this runs, so it moves to half open to let the trial fetch through.
+    pub fn before_fetch(&mut self) {
+        if self.state == CircuitState::Open {
+            info!("Circuit breaker half open, attempting a trial fetch.");
+            self.state = CircuitState::HalfOpen;
+        }
+    }
+
+    /// Resets the failure count and returns how long to wait before the next poll.
+    pub fn record_success(&mut self) -> Duration {
+        if self.state != CircuitState::Closed {
+            info!(
+                "Circuit breaker closed after {} consecutive failures.",
+                self.consecutive_failures
+            );
+        }
+        self.state = CircuitState::Closed;
+        self.consecutive_failures = 0;
+        self.config.interval
+    }
+
+    /// Records the failure and returns how long to wait before the next poll.
+    pub fn record_failure(&mut self, err: &FailedFetchError) -> Duration {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        self.last_failure_at = Some(err.0);
+        self.last_error = Some(err.1.clone());
+
+        if self.state == CircuitState::HalfOpen
+            || self.consecutive_failures >= self.config.breaker_threshold
+        {
+            if self.state != CircuitState::Open {
+                warn!(
+                    "Circuit breaker open after {} consecutive failures, waiting {} seconds.",
+                    self.consecutive_failures,
+                    self.config.breaker_cooldown.as_secs()
+                );
+            }
+            self.state = CircuitState::Open;
+            return self.config.breaker_cooldown;
+        }
+
+        let factor = 2u32.saturating_pow(self.consecutive_failures);
+        let backoff = self
+            .config
+            .interval
+            .saturating_mul(factor)
+            .min(self.config.max_backoff);
+        warn!(
+            "Fetch failed {} times in a row, backing off for {} seconds.",
+            self.consecutive_failures,
+            backoff.as_secs()
+        );
+        backoff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(BackoffConfig {
+            interval: Duration::from_secs(5),
+            max_backoff: Duration::from_secs(30),
+            breaker_threshold: 4,
+            breaker_cooldown: Duration::from_secs(300),
+        })
+    }
+
+    fn fail(breaker: &mut CircuitBreaker) -> Duration {
+        breaker.record_failure(&FailedFetchError(Utc::now(), "timed out".to_string()))
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let mut breaker = breaker();
+        let delays: Vec<u64> = (0..3).map(|_| fail(&mut breaker).as_secs()).collect();
+        assert_eq!(delays, vec![10, 20, 30]);
+        assert_eq!(breaker.state, CircuitState::Closed);
+        assert_eq!(breaker.last_error.as_deref(), Some("timed out"));
+    }
+
+    #[test]
+    fn threshold_opens_the_circuit() {
+        let mut breaker = breaker();
+        (0..3).for_each(|_| {
+            fail(&mut breaker);
+        });
+        assert_eq!(fail(&mut breaker), Duration::from_secs(300));
+        assert_eq!(breaker.state, CircuitState::Open);
+        assert_eq!(breaker.consecutive_failures, 4);
+
+        breaker.before_fetch();
+        assert_eq!(breaker.state, CircuitState::HalfOpen);
+    }
+
+    #[test]
+    fn failed_probe_reopens_the_circuit() {
+        let mut breaker = breaker();
+        breaker.state = CircuitState::Open;
+        breaker.before_fetch();
+        // Reopens on the probe even below the threshold.
+        assert_eq!(fail(&mut breaker), Duration::from_secs(300));
+        assert_eq!(breaker.state, CircuitState::Open);
+    }
+
+    #[test]
+    fn success_returns_to_the_normal_interval() {
+        let mut breaker = breaker();
+        (0..4).for_each(|_| {
+            fail(&mut breaker);
+        });
+        breaker.before_fetch();
+        assert_eq!(breaker.record_success(), Duration::from_secs(5));
+        assert_eq!(breaker.state, CircuitState::Closed);
+        assert_eq!(breaker.consecutive_failures, 0);
+        // The backoff starts over.
+        assert_eq!(fail(&mut breaker), Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,166 @@
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use futures::TryStreamExt;
+use serde::Serialize;
+use sqlx::{PgPool, Row};
+
+use crate::{config::SeptaConfig, db::QueryOrdering};
+
+/// Longest car number accepted, the same bound as the `record_cars` backfill's `^[0-9]{1,9}$`.
+/// Every such number fits the `int` column.
+const MAX_CAR_DIGITS: usize = 9;
+
+/// Car numbers in a consist string, ex: `"815,816,120"`, in the order they're listed.
+/// Placeholder entries like `TBD` and anything else that isn't a car number are skipped.
+pub fn parse_consist(consist: &str) -> Vec<u32> {
+    consist
+        .split(',')
+        // Spaces only, like postgres' `trim`.
+        .map(|car| car.trim_matches(' '))
+        .filter(|car| {
+            (1..=MAX_CAR_DIGITS).contains(&car.len()) && car.bytes().all(|b| b.is_ascii_digit())
+        })
+        .filter_map(|car| car.parse::<u32>().ok())
+        .collect()
+}
+
+/// An uninterrupted stretch of a car running in one train on one service day.
+#[derive(Debug, Serialize, Clone)]
+pub struct CarAppearance {
+    pub trainno: String,
+    #[serde(serialize_with = "crate::serde_utils::serialize_date")]
+    pub service_date: NaiveDate,
+    pub lines: Vec<String>,
+    #[serde(serialize_with = "crate::serde_utils::serialize_date_time")]
+    pub first_seen_at: DateTime<Utc>,
+    #[serde(serialize_with = "crate::serde_utils::serialize_date_time")]
+    pub last_seen_at: DateTime<Utc>,
+    pub record_count: usize,
+}
+
+impl CarAppearance {
+    /// Fetches up to `limit` appearances of a car, built from the records it was listed in.
+    pub async fn fetch_for_car(
+        pool: PgPool,
+        config: &SeptaConfig,
+        car_number: i32,
+        limit: i64,
+        before: Option<DateTime<Utc>>,
+        after: Option<DateTime<Utc>>,
+        order: Option<QueryOrdering>,
+    ) -> anyhow::Result<Vec<CarAppearance>> {
+        let mut builder = sqlx::QueryBuilder::new(
+            r#"select
+  records.trainno,
+  records.line,
+  records.received_at
+from
+    record_cars
+    join records on records.id = record_cars.record_id
+"#,
+        );
+        builder.push(" WHERE record_cars.car_number = ");
+        builder.push_bind(car_number);
+        if let Some(before) = before {
+            builder.push(" and records.received_at < ");
+            builder.push_bind(before);
+        }
+        if let Some(after) = after {
+            builder.push(" and records.received_at > ");
+            builder.push_bind(after);
+        }
+        builder.push(format!(
+            " ORDER BY records.received_at {}",
+            order.unwrap_or(QueryOrdering::DESC)
+        ));
+
+        let mut appearances: Vec<CarAppearance> = vec![];
+        let mut rows = builder.build().fetch(&pool);
+        while let Some(row) = rows.try_next().await? {
+            let trainno: String = row.get("trainno");
+            let line: String = row.get("line");
+            let received_at = row.get::<NaiveDateTime, &str>("received_at").and_utc();
+            let service_date = config.service_date(received_at);
+            match appearances.last_mut() {
+                Some(appearance)
+                    if appearance.trainno == trainno && appearance.service_date == service_date =>
+                {
+                    if !appearance.lines.contains(&line) {
+                        appearance.lines.push(line);
+                    }
+                    appearance.first_seen_at = appearance.first_seen_at.min(received_at);
+                    appearance.last_seen_at = appearance.last_seen_at.max(received_at);
+                    appearance.record_count += 1;
+                }
+                _ => {
+                    if appearances.len() as i64 >= limit {
+                        break;
+                    }
+                    appearances.push(CarAppearance {
+                        trainno,
+                        service_date,
+                        lines: vec![line],
+                        first_seen_at: received_at,
+                        last_seen_at: received_at,
+                        record_count: 1,
+                    });
+                }
+            }
+        }
+        Ok(appearances)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cars_in_listed_order() {
+        assert_eq!(parse_consist("815,816,120"), vec![815, 816, 120]);
+        assert_eq!(parse_consist(" 815 , 816,  120 "), vec![815, 816, 120]);
+    }
+
+    #[test]
+    fn placeholders_and_blanks_are_skipped() {
+        assert!(parse_consist("").is_empty());
+        assert!(parse_consist(" ").is_empty());
+        assert!(parse_consist(",,").is_empty());
+        assert_eq!(parse_consist("TBD,815,,8l6,-1,816"), vec![815, 816]);
+    }
+
+    #[test]
+    fn car_numbers_match_the_backfill() {
+        // The backfill's regex only takes up to nine digits, and no sign.
+        assert_eq!(parse_consist("999999999"), vec![999_999_999]);
+        assert!(parse_consist("1000000000").is_empty());
+        assert!(parse_consist("+815").is_empty());
+        assert_eq!(parse_consist("0815"), vec![815]);
+    }
+
+    /// Runs the backfill's trim and pattern over the same entries as `parse_consist`.
+    #[sqlx::test]
+    async fn backfill_pattern_agrees(pool: PgPool) {
+        for car in [
+            "815",
+            " 815 ",
+            "\t815",
+            "0",
+            "999999999",
+            "1000000000",
+            "2147483648",
+            "+815",
+            "-1",
+            "8 15",
+            "TBD",
+            "",
+            " ",
+        ] {
+            let backfilled: bool = sqlx::query_scalar("select trim($1) ~ '^[0-9]{1,9}$'")
+                .bind(car)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+            assert_eq!(backfilled, !parse_consist(car).is_empty(), "{car:?}");
+        }
+    }
+}
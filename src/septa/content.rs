@@ -3,13 +3,16 @@ use sqlx::PgPool;
 use tokio::io::AsyncWriteExt;
 use uuid::Uuid;
 
-use crate::septa::{processing::FILES_OUTPUT_DIR, train_view::TrainView};
+use crate::{db::tracking::Changed, metrics::METRICS, septa::train_view::TrainView};
 
 #[derive(Debug, Clone)]
 pub struct Content {
     pub timestamp: DateTime<Utc>,
     pub raw: String,
     pub trains: Vec<TrainView>,
+    /// The id of the archived file this was replayed from. Replayed payloads keep their file id,
+    /// aren't archived again, and aren't counted as fetches.
+    pub replayed_id: Option<Uuid>,
 }
 
 pub struct File {
@@ -18,18 +21,56 @@ pub struct File {
 }
 
 impl Content {
-    pub async fn commit_file(&self, id: Uuid, pg_pool: PgPool) -> anyhow::Result<File> {
+    pub fn from_raw(timestamp: DateTime<Utc>, raw: String) -> serde_json::Result<Content> {
+        let trains = serde_json::from_str::<Vec<TrainView>>(&raw)?;
+        Ok(Content {
+            timestamp,
+            raw,
+            trains,
+            replayed_id: None,
+        })
+    }
+
+    pub async fn commit_file(
+        &self,
+        id: Uuid,
+        changes: &[Changed],
+        output_dir: &str,
+        pg_pool: PgPool,
+    ) -> anyhow::Result<File> {
+        // The file, its records and their changes are saved together, so a failure part way
+        // through doesn't leave records whose changes were never saved.
+        let mut tx = pg_pool.begin().await?;
+        // A replayed file's row is usually still there from when it was first received.
         sqlx::query!(
-            "INSERT INTO files (id, received_at) VALUES ($1, $2)",
+            "INSERT INTO files (id, received_at) VALUES ($1, $2) ON CONFLICT (id) DO NOTHING",
             id,
             self.timestamp.naive_utc(),
         )
-        .execute(&pg_pool)
+        .execute(&mut *tx)
         .await?;
+        if self.replayed_id.is_some() {
+            // Replaying a file replaces whatever an earlier run stored for it.
+            sqlx::query!(
+                "DELETE FROM record_cars WHERE record_id IN (SELECT id FROM records WHERE file_id = $1)",
+                id
+            )
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query!(
+                "DELETE FROM changes WHERE record_id IN (SELECT id FROM records WHERE file_id = $1)",
+                id
+            )
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query!("DELETE FROM records WHERE file_id = $1", id)
+                .execute(&mut *tx)
+                .await?;
+        }
 
-        {
+        if self.replayed_id.is_none() {
             let contents = self.raw.clone();
-            let path = format!("{}/{}.json", FILES_OUTPUT_DIR, id);
+            let path = format!("{}/{}.json", output_dir, id);
             tokio::spawn(async move {
                 let mut file = match tokio::fs::File::create(&path).await {
                     Ok(file) => file,
@@ -44,7 +85,6 @@ impl Content {
                     }
                     Err(err) => {
                         error!("Failed to write file to file system: {:?}", err);
-                        return;
                     }
                 };
             });
@@ -54,7 +94,11 @@ impl Content {
             id,
             received_at: self.timestamp,
         };
-        TrainView::commit_new_records(&self.trains, &file, pg_pool).await?;
+        let timer = METRICS.db_insert_duration.start_timer();
+        TrainView::commit_new_records(&self.trains, &file, &mut tx).await?;
+        timer.observe_duration();
+        Changed::commit_changes(changes, &mut tx).await?;
+        tx.commit().await?;
 
         Ok(file)
     }
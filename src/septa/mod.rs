@@ -1,5 +1,10 @@
 pub mod api;
+pub mod backoff;
+pub mod consist;
 pub mod content;
 pub mod processing;
 pub mod query_builder;
+pub mod replay;
+pub mod stations;
+pub mod train_run;
 pub mod train_view;
@@ -1,29 +1,36 @@
-use super::api;
+use super::{api::TrainSource, replay};
 use chrono::{DateTime, Days, Local, Utc};
 use serde_json::json;
-use std::{collections::HashMap, io::ErrorKind, sync::Arc, time::Duration};
+use std::{collections::HashMap, io::ErrorKind, path::PathBuf, sync::Arc, time::Duration};
 use tokio::{
     fs,
-    sync::mpsc::{Receiver, Sender},
+    sync::{
+        broadcast,
+        mpsc::{Receiver, Sender},
+    },
     task::JoinHandle,
 };
 
 use crate::{
     SharedAppState,
-    db::tracking::{Fetch, Tracking},
+    config::FilesConfig,
+    db::tracking::{ChangeEvent, Changed, Fetch, Tracking},
+    metrics::METRICS,
     septa::content::Content,
-    septa::train_view::TrainView,
+    septa::stations::STATIONS,
+    septa::train_view::{TrainUpdate, TrainView},
 };
 
-pub const FILES_OUTPUT_DIR: &'static str = "./files";
-pub const POLL_INTERVAL: u64 = 5;
-
-pub async fn start(state: SharedAppState) -> anyhow::Result<(JoinHandle<()>, JoinHandle<()>)> {
+pub async fn start<S: TrainSource>(
+    state: SharedAppState,
+    source: S,
+) -> anyhow::Result<(JoinHandle<()>, JoinHandle<()>)> {
     let state_handle = state.clone();
     let (file_sender, file_receiver) = tokio::sync::mpsc::channel(1);
-    ensure_directories_created().await;
+    let files_config = state.read().await.config.files.clone();
+    ensure_directories_created(&files_config.output_dir).await;
     let poll_handle = tokio::spawn(async move {
-        let _ = poll_for_train_view(state_handle, POLL_INTERVAL, file_sender).await;
+        let _ = poll_for_train_view(state_handle, source, file_sender).await;
     });
 
     let state_handle = state.clone();
@@ -31,13 +38,36 @@ pub async fn start(state: SharedAppState) -> anyhow::Result<(JoinHandle<()>, Joi
         let _ = accept_new_file(state_handle, file_receiver).await;
     });
     let _output_dir_watchdog = tokio::spawn(async move {
-        let _ = schedule_file_cleanup_job().await;
+        let _ = schedule_file_cleanup_job(files_config).await;
     });
     Ok((poll_handle, processer_handle))
 }
 
-pub async fn ensure_directories_created() {
-    match tokio::fs::create_dir(FILES_OUTPUT_DIR).await {
+/// Feeds the archived payloads in `dir` through the same pipeline as the poller instead of
+/// fetching from Septa. The processing task exits once every file has been replayed.
+pub async fn start_replay(
+    state: SharedAppState,
+    dir: PathBuf,
+) -> anyhow::Result<(JoinHandle<()>, JoinHandle<()>)> {
+    let state_handle = state.clone();
+    let (file_sender, file_receiver) = tokio::sync::mpsc::channel(1);
+    ensure_directories_created(&state.read().await.config.files.output_dir).await;
+    let replay_handle = tokio::spawn(async move {
+        match replay::replay_directory(state_handle, dir, file_sender).await {
+            Ok(sent) => info!("Replay completed. Sent {sent} files."),
+            Err(e) => error!("Replay failed: {e:?}"),
+        }
+    });
+
+    let state_handle = state.clone();
+    let processer_handle = tokio::spawn(async move {
+        let _ = accept_new_file(state_handle, file_receiver).await;
+    });
+    Ok((replay_handle, processer_handle))
+}
+
+pub async fn ensure_directories_created(output_dir: &str) {
+    match tokio::fs::create_dir(output_dir).await {
         Ok(_) => {
             warn!("Output directory created.");
             Ok(())
@@ -53,32 +83,31 @@ pub async fn ensure_directories_created() {
 
 pub async fn accept_new_file(state: SharedAppState, mut recv: Receiver<Content>) {
     while let Some(mut content) = recv.recv().await {
+        METRICS.channel_depth.set(recv.len() as i64);
         let incomming_len = content.trains.len();
         {
-            let statuses = &state.read().await.train_statuses;
-            content.trains.retain(|tv| {
-                if let Some(existing) = statuses.get(&tv.trainno) {
-                    if let Some(ref mri) = existing.most_recent_item {
-                        if **mri != *tv {
-                            return true;
-                        } else {
-                            return false;
-                        }
-                    } else {
-                        return false;
-                    }
-                }
-                return true;
-            });
+            let mut state = state.write().await;
+            let state = &mut *state;
+            refresh_unchanged(
+                &mut content.trains,
+                &content.timestamp,
+                &mut state.train_statuses,
+                &state.train_sender,
+            );
         }
 
-        if content.trains.len() == 0 {
+        if content.trains.is_empty() {
             // TODO: Should i drop the file if there's no "changed" trains, should i keep it but
             // just not keep a record?
             info!("File is not changed.");
-            let _ = Fetch::new(content.timestamp, "UNCHANGED".to_string(), None)
-                .store_fetch(state.read().await.pg_pool.clone())
-                .await;
+            METRICS.cycle_trains_processed.set(0);
+            METRICS.cycle_trains_updated.set(0);
+            if content.replayed_id.is_none() {
+                METRICS.fetches.with_label_values(&["UNCHANGED"]).inc();
+                let _ = Fetch::new(content.timestamp, "UNCHANGED".to_string(), None)
+                    .store_fetch(state.read().await.pg_pool.clone())
+                    .await;
+            }
             continue;
         }
         debug!(
@@ -87,68 +116,100 @@ pub async fn accept_new_file(state: SharedAppState, mut recv: Receiver<Content>)
             incomming_len
         );
 
-        let file_id = uuid::Uuid::new_v4();
-        {
-            let state = state.clone();
-            let content = content.clone();
-            let file_id = file_id.clone();
-            tokio::spawn(async move {
-                match content
-                    .commit_file(file_id, state.read().await.pg_pool.clone())
-                    .await
-                {
-                    Ok(_) => {}
-                    Err(err) => {
-                        error!("Failed to execute commit_file: {:?}", err);
-                    }
-                }
-            });
-        }
+        let file_id = content.replayed_id.unwrap_or_else(uuid::Uuid::new_v4);
         let len = content.trains.len();
-        content
-            .trains
-            .iter_mut()
-            .for_each(|tv| tv.file_id = file_id);
+        content.trains.iter_mut().for_each(|tv| {
+            tv.file_id = file_id;
+            tv.normalise_stations(&STATIONS);
+        });
 
-        let updated = process_train_views(
-            content.trains,
+        let processed = process_train_views(
+            content.trains.clone(),
             &content.timestamp,
             &mut state.write().await.train_statuses,
         );
-        let result = json!({
-            "updated": updated,
-            "incomming": incomming_len,
-        })
-        .to_string();
-        let _ = Fetch::new(content.timestamp, "OK".to_string(), Some(result))
-            .store_fetch(state.read().await.pg_pool.clone())
-            .await;
+        let updated = processed.updated;
+        // Committed before the next file is taken, and published only once saved, so a live
+        // subscriber catching up from the database can't miss or reorder anything.
+        let (output_dir, pg_pool) = {
+            let state = state.read().await;
+            (state.config.files.output_dir.clone(), state.pg_pool.clone())
+        };
+        match content
+            .commit_file(file_id, &processed.changes, &output_dir, pg_pool)
+            .await
+        {
+            Ok(_) => {
+                let state = state.read().await;
+                processed.publish(&state.change_sender, &state.train_sender);
+            }
+            Err(err) => {
+                error!("Failed to execute commit_file: {:?}", err);
+            }
+        }
+        METRICS.trains_processed.inc_by(len as u64);
+        METRICS.trains_updated.inc_by(updated as u64);
+        METRICS.cycle_trains_processed.set(len as i64);
+        METRICS.cycle_trains_updated.set(updated as i64);
+        if content.replayed_id.is_none() {
+            METRICS.fetches.with_label_values(&["OK"]).inc();
+            let result = json!({
+                "updated": updated,
+                "incomming": incomming_len,
+            })
+            .to_string();
+            let _ = Fetch::new(content.timestamp, "OK".to_string(), Some(result))
+                .store_fetch(state.read().await.pg_pool.clone())
+                .await;
+        }
         info!("Processed {len} updates. Wrote {updated}.");
     }
 }
 
-pub async fn poll_for_train_view(state: SharedAppState, interval: u64, sender: Sender<Content>) {
-    let sleep_duration = Duration::from_secs(interval);
+/// Polls `source` until it's exhausted, backing off after failed fetches according to the circuit
+/// breaker in the app state.
+pub async fn poll_for_train_view<S: TrainSource>(
+    state: SharedAppState,
+    source: S,
+    sender: Sender<Content>,
+) {
     loop {
-        match api::fetch_train_view().await {
-            Ok(content) => match sender.send(content).await {
-                Err(e) => {
+        if source.is_exhausted() {
+            info!("Train source exhausted. Stopped polling.");
+            break;
+        }
+        state.write().await.circuit_breaker.before_fetch();
+        let timer = METRICS.fetch_duration.start_timer();
+        let fetched = source.fetch_train_view().await;
+        timer.observe_duration();
+        let sleep_duration = match fetched {
+            Ok(content) => {
+                let sleep_duration = state.write().await.circuit_breaker.record_success();
+                if let Err(e) = sender.send(content).await {
                     error!("Sender failed: {e:?}");
                     break;
                 }
-                _ => {}
-            },
+                METRICS
+                    .channel_depth
+                    .set((sender.max_capacity() - sender.capacity()) as i64);
+                sleep_duration
+            }
             Err(e) => {
+                let sleep_duration = state.write().await.circuit_breaker.record_failure(&e);
+                METRICS.fetches.with_label_values(&["FETCH_ERROR"]).inc();
                 let _ = Fetch::new(e.0, "FETCH_ERROR".to_string(), Some(e.1))
                     .store_fetch(state.read().await.pg_pool.clone())
                     .await;
+                sleep_duration
             }
+        };
+        if !source.is_exhausted() {
+            tokio::time::sleep(sleep_duration).await;
         }
-        tokio::time::sleep(sleep_duration).await;
     }
 }
 
-pub async fn schedule_file_cleanup_job() {
+pub async fn schedule_file_cleanup_job(config: FilesConfig) {
     let sleep_duration = Duration::from_secs(60 * 60); // 1 Hour
     info!(
         "Started file cleanup watchdog, scheduled to run every {} seconds. ",
@@ -157,14 +218,16 @@ pub async fn schedule_file_cleanup_job() {
     loop {
         info!("Starting file cleanup task.");
         let mut removed = 0;
-        let last_week = chrono::Local::now().checked_sub_days(Days::new(7)).unwrap();
-        match fs::read_dir(FILES_OUTPUT_DIR).await {
+        let cutoff = chrono::Local::now()
+            .checked_sub_days(Days::new(config.retention_days))
+            .unwrap();
+        match fs::read_dir(&config.output_dir).await {
             Ok(mut files) => {
                 while let Ok(Some(file)) = files.next_entry().await {
                     match file.metadata().await.and_then(|meta| meta.modified()) {
                         Ok(btime) => {
                             let created_time = chrono::DateTime::<Local>::from(btime);
-                            if created_time < last_week {
+                            if created_time < cutoff {
                                 removed += 1;
                                 let path = file.path().clone();
                                 tokio::spawn(async move {
@@ -187,14 +250,89 @@ pub async fn schedule_file_cleanup_job() {
     }
 }
 
+/// What processing a file changed, to be saved and then published to live subscribers.
+struct Processed {
+    updated: usize,
+    /// Ordered by `(changed_at, id)`, the order live subscribers resume in.
+    changes: Vec<Changed>,
+    /// The line each changed train reported.
+    lines: HashMap<String, String>,
+    train_updates: Vec<Arc<TrainUpdate>>,
+}
+
+impl Processed {
+    /// Publishes every newer view to `train_sender` and every change to `change_sender`. No
+    /// receivers is the normal case when nobody's streaming, so send errors are ignored.
+    fn publish(
+        self,
+        change_sender: &broadcast::Sender<ChangeEvent>,
+        train_sender: &broadcast::Sender<Arc<TrainUpdate>>,
+    ) {
+        if train_sender.receiver_count() > 0 {
+            self.train_updates.into_iter().for_each(|update| {
+                let _ = train_sender.send(update);
+            });
+        }
+        self.changes.into_iter().for_each(|change| {
+            let line = self.lines.get(&change.trainno).cloned().unwrap_or_default();
+            let _ = change_sender.send(ChangeEvent { change, line });
+        });
+    }
+}
+
+/// Drops the trains that haven't changed since their tracked view, so no record is written for
+/// them. Their position still moves between polls though, so the tracked view takes the new
+/// `lat`/`lon`/`heading` and `seen_at`, and live subscribers get it with no changes when it
+/// moved.
+fn refresh_unchanged(
+    trains: &mut Vec<TrainView>,
+    timestamp: &DateTime<Utc>,
+    train_statuses: &mut HashMap<String, Tracking<TrainView>>,
+    train_sender: &broadcast::Sender<Arc<TrainUpdate>>,
+) {
+    trains.retain(|train| {
+        let Some(tracking) = train_statuses.get_mut(&train.trainno) else {
+            return true;
+        };
+        let Some(ref mut tracked) = tracking.most_recent_item else {
+            return false;
+        };
+        if **tracked != *train {
+            return true;
+        }
+        if tracked.last_seen() >= *timestamp {
+            return false;
+        }
+        let moved =
+            (tracked.lat, tracked.lon, tracked.heading) != (train.lat, train.lon, train.heading);
+        let tracked = Arc::make_mut(tracked);
+        tracked.lat = train.lat;
+        tracked.lon = train.lon;
+        tracked.heading = train.heading;
+        tracked.seen_at = Some(*timestamp);
+        if moved && train_sender.receiver_count() > 0 {
+            let _ = train_sender.send(Arc::new(TrainUpdate {
+                train: tracked.clone(),
+                changes: vec![],
+            }));
+        }
+        false
+    });
+}
+
+/// Updates the tracked statuses with the new train views.
 fn process_train_views(
     train_views: Vec<TrainView>,
     timestamp: &DateTime<Utc>,
     train_statuses: &mut HashMap<String, Tracking<TrainView>>,
-) -> usize {
+) -> Processed {
     let mut updated = 0;
+    let mut all_changes = vec![];
+    let mut lines: HashMap<String, String> = HashMap::new();
+    let mut train_updates = vec![];
     train_views.into_iter().for_each(|mut train_view| {
-        train_view.timestamp = timestamp.clone();
+        train_view.timestamp = *timestamp;
+        train_view.seen_at = Some(*timestamp);
         if !train_statuses.contains_key(&train_view.trainno) {
             train_statuses.insert(train_view.trainno.to_owned(), Tracking::default());
         }
@@ -202,14 +340,227 @@ fn process_train_views(
         let train_view = Arc::new(train_view);
         if *timestamp > views.most_recent_timestamp {
             if let Some(ref most_recent) = views.most_recent_item {
-                let changes = train_view.get_changes(&most_recent);
+                let changes = train_view.get_changes(most_recent);
+                if let Some(ref changes) = changes {
+                    all_changes.extend(changes.iter().cloned());
+                    lines.insert(train_view.trainno.clone(), train_view.line.clone());
+                }
                 views.latest_changes = changes;
                 updated += 1;
             }
             views.most_recent_timestamp = *timestamp;
             views.most_recent_item = Some(train_view.clone());
+            train_updates.push(Arc::new(TrainUpdate {
+                train: (*train_view).clone(),
+                changes: views.latest_changes.clone().unwrap_or_default(),
+            }));
         }
         // views.items.push(train_view);
     });
-    updated
+
+    all_changes.sort_by_key(|change| (change.changed_at, change.id));
+    Processed {
+        updated,
+        changes: all_changes,
+        lines,
+        train_updates,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        AppState,
+        config::Config,
+        septa::{
+            api::FixtureSource,
+            backoff::{BackoffConfig, CircuitBreaker},
+        },
+    };
+    use sqlx::{PgPool, Row};
+    use tokio::sync::RwLock;
+
+    /// Three polls of `tests/fixtures/trainview`: two trains seen, then 1001 running later while
+    /// 1002 only moves, then 1001 only moving while 1002 reaches its next stop and 1003 appears.
+    fn fixture_source() -> FixtureSource {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/trainview");
+        FixtureSource::from_files(
+            ["1.json", "2.json", "3.json"]
+                .map(|name| dir.join(name))
+                .into(),
+        )
+    }
+
+    /// State for polling the fixtures back to back. The zero poll interval would fail
+    /// `Config::validate`, which is deliberately skipped here.
+    fn test_state(pg_pool: PgPool, output_dir: &str) -> SharedAppState {
+        let mut config = Config::default();
+        config.septa.poll_interval_secs = 0;
+        config.files.output_dir = output_dir.to_string();
+        Arc::new(RwLock::new(AppState {
+            train_statuses: HashMap::new(),
+            pg_pool,
+            circuit_breaker: CircuitBreaker::new(BackoffConfig::from(&config.septa)),
+            config: Arc::new(config),
+            change_sender: broadcast::channel(16).0,
+            train_sender: broadcast::channel(16).0,
+        }))
+    }
+
+    #[sqlx::test]
+    async fn polled_fixtures_store_records_and_changes(pg_pool: PgPool) {
+        let output_dir = std::env::temp_dir().join(format!("septa-test-{}", uuid::Uuid::new_v4()));
+        let output_dir = output_dir.to_string_lossy().into_owned();
+        ensure_directories_created(&output_dir).await;
+        let state = test_state(pg_pool.clone(), &output_dir);
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+
+        // The poller stops once the fixtures run out, dropping the sender, which ends processing.
+        tokio::join!(
+            poll_for_train_view(state.clone(), fixture_source(), sender),
+            accept_new_file(state.clone(), receiver),
+        );
+
+        let records: Vec<(String, String, Option<String>, i32)> = sqlx::query(
+            "select trainno, currentstop, currentstop_id, late from records order by trainno, received_at",
+        )
+        .fetch_all(&pg_pool)
+        .await
+        .unwrap()
+        .iter()
+        .map(|row| {
+            (
+                row.get("trainno"),
+                row.get("currentstop"),
+                row.get("currentstop_id"),
+                row.get("late"),
+            )
+        })
+        .collect();
+        let record = |trainno: &str, stop: &str, stop_id: &str, late| {
+            (trainno.into(), stop.into(), Some(stop_id.into()), late)
+        };
+        assert_eq!(
+            records,
+            vec![
+                record("1001", "Ardmore", "ardmore", 0),
+                record("1001", "Ardmore", "ardmore", 2),
+                record("1002", "30th Street Station", "gray-30th-street", 1),
+                record("1002", "Overbrook", "overbrook", 1),
+                record("1003", "30th Street Station", "gray-30th-street", 0),
+            ]
+        );
+
+        let changes: Vec<(String, String, Option<String>, Option<String>)> = sqlx::query(
+            "select trainno, field, old_value, new_value from changes order by trainno, field",
+        )
+        .fetch_all(&pg_pool)
+        .await
+        .unwrap()
+        .iter()
+        .map(|row| {
+            (
+                row.get("trainno"),
+                row.get("field"),
+                row.get("old_value"),
+                row.get("new_value"),
+            )
+        })
+        .collect();
+        let change = |trainno: &str, field: &str, old: &str, new: &str| {
+            (
+                trainno.into(),
+                field.into(),
+                Some(old.into()),
+                Some(new.into()),
+            )
+        };
+        assert_eq!(
+            changes,
+            vec![
+                change("1001", "late", "0", "2"),
+                change("1002", "currentstop", "30th Street Station", "Overbrook"),
+                change("1002", "nextstop", "Overbrook", "Merion"),
+            ]
+        );
+
+        // Every fixture was fetched once, and running out of them isn't a failed fetch.
+        let statuses: Vec<String> = sqlx::query("select status from fetches order by timestamp")
+            .fetch_all(&pg_pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get("status"))
+            .collect();
+        assert_eq!(statuses, vec!["OK", "OK", "OK"]);
+        let files: i64 = sqlx::query("select count(*) from files")
+            .fetch_one(&pg_pool)
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(files, 3);
+
+        // 1001 wasn't written on the last poll, but its tracked position still moved.
+        let state = state.read().await;
+        let tracked = state.train_statuses["1001"]
+            .most_recent_item
+            .clone()
+            .unwrap();
+        assert_eq!((tracked.late, tracked.lat), (2, Some(40.014)));
+        assert!(tracked.seen_at.unwrap() > tracked.timestamp);
+
+        let _ = tokio::fs::remove_dir_all(&output_dir).await;
+    }
+
+    async fn count(pg_pool: &PgPool, table: &str) -> i64 {
+        sqlx::query(&format!("select count(*) from {table}"))
+            .fetch_one(pg_pool)
+            .await
+            .unwrap()
+            .get(0)
+    }
+
+    #[sqlx::test]
+    async fn replaying_a_file_again_replaces_its_records(pg_pool: PgPool) {
+        let fixtures = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/trainview");
+        let replay_dir = std::env::temp_dir().join(format!("septa-test-{}", uuid::Uuid::new_v4()));
+        let output_dir = replay_dir.join("output").to_string_lossy().into_owned();
+        fs::create_dir_all(&replay_dir).await.unwrap();
+        let started = std::time::SystemTime::now() - Duration::from_secs(60);
+        for (i, name) in ["1.json", "2.json"].into_iter().enumerate() {
+            let path = replay_dir.join(format!("{}.json", uuid::Uuid::new_v4()));
+            fs::copy(fixtures.join(name), &path).await.unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(started + Duration::from_secs(i as u64))
+                .unwrap();
+        }
+
+        let mut counts = vec![];
+        for _ in 0..2 {
+            // A fresh state each time, like restarting with the same `REPLAY_DIR`.
+            let state = test_state(pg_pool.clone(), &output_dir);
+            let (sender, receiver) = tokio::sync::mpsc::channel(1);
+            let (sent, _) = tokio::join!(
+                replay::replay_directory(state.clone(), replay_dir.clone(), sender),
+                accept_new_file(state.clone(), receiver),
+            );
+            assert_eq!(sent.unwrap(), 2);
+            counts.push((
+                count(&pg_pool, "records").await,
+                count(&pg_pool, "record_cars").await,
+                count(&pg_pool, "changes").await,
+                count(&pg_pool, "files").await,
+            ));
+        }
+        assert_eq!(counts[0], (3, 6, 1, 2));
+        assert_eq!(counts[0], counts[1]);
+        // Replays aren't fetches.
+        assert_eq!(count(&pg_pool, "fetches").await, 0);
+
+        let _ = fs::remove_dir_all(&replay_dir).await;
+    }
 }
@@ -1,38 +1,251 @@
 /// TODO: Implement this dynamically with proc_macros
-use serde::Deserialize;
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Deserializer, de::DeserializeOwned};
+use sqlx::{Encode, Postgres, Row, Type, postgres::PgRow};
 use uuid::Uuid;
 
-const DEFAULT_RESPONSE_FIELDS: [&'static str; 12] = [
-    "records.id",
-    "file_id",
-    "trainno",
-    "service",
-    "dest",
-    "currentstop",
-    "nextstop",
-    "line",
-    "consist",
-    "late",
-    "source",
-    "received_at",
-];
+use crate::septa::train_view::{RECORD_COLUMNS, select_list};
+
+#[derive(Debug, Clone, Copy)]
+enum FieldType {
+    Uuid,
+    Text,
+    Integer,
+    Real,
+    /// Returned as unix seconds, like every other timestamp in the api.
+    Timestamp,
+}
+
+/// Clients can select any of `RECORD_COLUMNS`.
+fn field_type(field: &str) -> Option<FieldType> {
+    let field_type = match field {
+        "id" | "file_id" => FieldType::Uuid,
+        "late" => FieldType::Integer,
+        "lat" | "lon" | "heading" => FieldType::Real,
+        "received_at" => FieldType::Timestamp,
+        _ => FieldType::Text,
+    };
+    RECORD_COLUMNS.contains(&field).then_some(field_type)
+}
+
+/// Reads the selected `fields` of a row into a JSON object keyed by column name.
+pub fn row_to_json(row: &PgRow, fields: &[String]) -> serde_json::Map<String, serde_json::Value> {
+    fields
+        .iter()
+        .filter_map(|field| {
+            let value = match field_type(field)? {
+                FieldType::Uuid => serde_json::json!(row.get::<Option<Uuid>, &str>(field.as_str())),
+                FieldType::Text => {
+                    serde_json::json!(row.get::<Option<String>, &str>(field.as_str()))
+                }
+                FieldType::Integer => {
+                    serde_json::json!(row.get::<Option<i32>, &str>(field.as_str()))
+                }
+                // Through the shortest f32 representation, so 39.98 doesn't widen to
+                // 39.97999954223633.
+                FieldType::Real => serde_json::json!(
+                    row.get::<Option<f32>, &str>(field.as_str())
+                        .and_then(|value| value.to_string().parse::<f64>().ok())
+                ),
+                FieldType::Timestamp => serde_json::json!(
+                    row.get::<Option<NaiveDateTime>, &str>(field.as_str())
+                        .map(|timestamp| timestamp.and_utc().timestamp())
+                ),
+            };
+            Some((field.clone(), value))
+        })
+        .collect()
+}
+
+/// A filter on one column. Either a bare value, matched with `=`, or an object of operators
+/// which must all hold, e.g. `{"gte": 5, "lt": 15}`.
+#[derive(Debug, Clone)]
+pub enum Filter<T> {
+    Value(T),
+    Condition(Condition<T>),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, bound = "T: DeserializeOwned")]
+pub struct Condition<T> {
+    pub eq: Option<T>,
+    #[serde(rename = "in")]
+    pub in_: Option<Vec<T>>,
+    /// Matches anything the inner filter doesn't.
+    pub not: Option<Box<Filter<T>>>,
+    pub gt: Option<T>,
+    pub gte: Option<T>,
+    pub lt: Option<T>,
+    pub lte: Option<T>,
+    pub like: Option<String>,
+    pub ilike: Option<String>,
+}
+
+impl<T> From<T> for Filter<T> {
+    fn from(value: T) -> Self {
+        Filter::Value(value)
+    }
+}
+
+// Deserialized through a `serde_json::Value` rather than `untagged`, so a bad operator gets
+// serde's error for it instead of "data did not match any variant".
+impl<'de, T: DeserializeOwned> Deserialize<'de> for Filter<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        if value.is_object() {
+            serde_json::from_value(value).map(Filter::Condition)
+        } else {
+            serde_json::from_value(value).map(Filter::Value)
+        }
+        .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Which operators beyond `eq`, `in` and `not` a column allows.
+#[derive(Clone, Copy, PartialEq)]
+enum Operators {
+    Exact,
+    /// `gt`, `gte`, `lt` and `lte`.
+    Range,
+    /// `like` and `ilike`.
+    Pattern,
+}
+
+impl<T> Filter<T> {
+    fn validate(&self, field: &str, operators: Operators) -> Result<(), String> {
+        let Filter::Condition(condition) = self else {
+            return Ok(());
+        };
+        if operators != Operators::Range
+            && [&condition.gt, &condition.gte, &condition.lt, &condition.lte]
+                .iter()
+                .any(|op| op.is_some())
+        {
+            return Err(format!("{field} does not support gt, gte, lt or lte"));
+        }
+        if operators != Operators::Pattern
+            && (condition.like.is_some() || condition.ilike.is_some())
+        {
+            return Err(format!("{field} does not support like or ilike"));
+        }
+        if condition
+            .in_
+            .as_ref()
+            .is_some_and(|values| values.is_empty())
+        {
+            return Err(format!("{field}.in must not be empty"));
+        }
+        match condition.not {
+            Some(ref not) => not.validate(field, operators),
+            None => Ok(()),
+        }
+    }
+
+    /// Every value the filter compares against, including under `not`.
+    fn values(&self) -> Vec<&T> {
+        match self {
+            Filter::Value(value) => vec![value],
+            Filter::Condition(condition) => {
+                let mut values: Vec<&T> = [
+                    &condition.eq,
+                    &condition.gt,
+                    &condition.gte,
+                    &condition.lt,
+                    &condition.lte,
+                ]
+                .into_iter()
+                .flatten()
+                .collect();
+                values.extend(condition.in_.iter().flatten());
+                values.extend(condition.not.iter().flat_map(|not| not.values()));
+                values
+            }
+        }
+    }
+
+    /// Pushes this filter as a parenthesized expression on `column`, binding every value after
+    /// mapping it with `to_sql`.
+    fn push<'b, B, F>(self, builder: &mut sqlx::QueryBuilder<'b, Postgres>, column: &str, to_sql: F)
+    where
+        B: 'b + Encode<'b, Postgres> + Type<Postgres> + Send,
+        F: Fn(T) -> B + Copy,
+    {
+        let condition = match self {
+            Filter::Value(value) => Condition {
+                eq: Some(value),
+                in_: None,
+                not: None,
+                gt: None,
+                gte: None,
+                lt: None,
+                lte: None,
+                like: None,
+                ilike: None,
+            },
+            Filter::Condition(condition) => condition,
+        };
+
+        builder.push("(true");
+        macro_rules! comparison {
+            ($op:ident, $sql:literal) => {
+                if let Some(value) = condition.$op {
+                    builder.push(format!(" and {} {} ", column, $sql));
+                    builder.push_bind(to_sql(value));
+                }
+            };
+        }
+        comparison!(eq, "=");
+        comparison!(gt, ">");
+        comparison!(gte, ">=");
+        comparison!(lt, "<");
+        comparison!(lte, "<=");
+        if let Some(values) = condition.in_ {
+            builder.push(format!(" and {} in (", column));
+            let mut separated = builder.separated(", ");
+            values.into_iter().for_each(|value| {
+                separated.push_bind(to_sql(value));
+            });
+            builder.push(")");
+        }
+        if let Some(pattern) = condition.like {
+            builder.push(format!(" and {} like ", column));
+            builder.push_bind(pattern);
+        }
+        if let Some(pattern) = condition.ilike {
+            builder.push(format!(" and {} ilike ", column));
+            builder.push_bind(pattern);
+        }
+        if let Some(not) = condition.not {
+            builder.push(" and not ");
+            not.push(builder, column, to_sql);
+        }
+        builder.push(")");
+    }
+}
 
 #[derive(Default, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct QueryBuilder {
-    pub id: Option<Uuid>,
-    pub file_id: Option<Uuid>,
-    pub timestamp: Option<i64>,
-    pub trainno: Option<String>,
-    pub service: Option<String>,
-    pub dest: Option<String>,
-    pub currentstop: Option<String>,
-    pub nextstop: Option<String>,
-    pub line: Option<String>,
-    pub consist: Option<String>,
-    pub late: Option<i32>,
-    pub source: Option<String>,
-    #[serde(skip)]
+    pub id: Option<Filter<Uuid>>,
+    pub file_id: Option<Filter<Uuid>>,
+    /// Matched against `received_at`, in unix seconds.
+    pub timestamp: Option<Filter<i64>>,
+    pub trainno: Option<Filter<String>>,
+    pub service: Option<Filter<String>>,
+    pub dest: Option<Filter<String>>,
+    pub currentstop: Option<Filter<String>>,
+    pub nextstop: Option<Filter<String>>,
+    pub line: Option<Filter<String>>,
+    pub consist: Option<Filter<String>>,
+    pub late: Option<Filter<i32>>,
+    pub source: Option<Filter<String>>,
+    pub track: Option<Filter<String>>,
+    pub track_change: Option<Filter<String>>,
+    pub currentstop_id: Option<Filter<String>>,
+    pub nextstop_id: Option<Filter<String>>,
+    pub source_id: Option<Filter<String>>,
+    pub dest_id: Option<Filter<String>>,
+    /// Columns to return, from `RECORD_COLUMNS`. All of them when not set.
     pub fields: Option<Vec<String>>,
 }
 
@@ -41,72 +254,104 @@ impl QueryBuilder {
     pub fn new() -> Self {
         Self::default()
     }
-    pub fn with_id(mut self, id: Uuid) -> Self {
-        self.id = Some(id);
+    pub fn with_id(mut self, id: impl Into<Filter<Uuid>>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+    pub fn with_file_id(mut self, file_id: impl Into<Filter<Uuid>>) -> Self {
+        self.file_id = Some(file_id.into());
+        self
+    }
+    pub fn with_timestamp(mut self, timestamp: impl Into<Filter<i64>>) -> Self {
+        self.timestamp = Some(timestamp.into());
+        self
+    }
+    pub fn with_trainno(mut self, trainno: impl Into<Filter<String>>) -> Self {
+        self.trainno = Some(trainno.into());
         self
     }
-    pub fn with_file_id(mut self, file_id: Uuid) -> Self {
-        self.file_id = Some(file_id);
+    pub fn with_service(mut self, service: impl Into<Filter<String>>) -> Self {
+        self.service = Some(service.into());
         self
     }
-    pub fn with_timestamp(mut self, timestamp: i64) -> Self {
-        self.timestamp = Some(timestamp);
+    pub fn with_dest(mut self, dest: impl Into<Filter<String>>) -> Self {
+        self.dest = Some(dest.into());
         self
     }
-    pub fn with_trainno(mut self, trainno: String) -> Self {
-        self.trainno = Some(trainno);
+    pub fn with_currentstop(mut self, currentstop: impl Into<Filter<String>>) -> Self {
+        self.currentstop = Some(currentstop.into());
         self
     }
-    pub fn with_service(mut self, service: String) -> Self {
-        self.service = Some(service);
+    pub fn with_nextstop(mut self, nextstop: impl Into<Filter<String>>) -> Self {
+        self.nextstop = Some(nextstop.into());
         self
     }
-    pub fn with_dest(mut self, dest: String) -> Self {
-        self.dest = Some(dest);
+    pub fn with_line(mut self, line: impl Into<Filter<String>>) -> Self {
+        self.line = Some(line.into());
         self
     }
-    pub fn with_currentstop(mut self, currentstop: String) -> Self {
-        self.currentstop = Some(currentstop);
+    pub fn with_consist(mut self, consist: impl Into<Filter<String>>) -> Self {
+        self.consist = Some(consist.into());
         self
     }
-    pub fn with_nextstop(mut self, nextstop: String) -> Self {
-        self.nextstop = Some(nextstop);
+    pub fn with_late(mut self, late: impl Into<Filter<i32>>) -> Self {
+        self.late = Some(late.into());
         self
     }
-    pub fn with_line(mut self, line: String) -> Self {
-        self.line = Some(line);
+    pub fn with_source(mut self, source: impl Into<Filter<String>>) -> Self {
+        self.source = Some(source.into());
         self
     }
-    pub fn with_consist(mut self, consist: String) -> Self {
-        self.consist = Some(consist);
+    pub fn with_track(mut self, track: impl Into<Filter<String>>) -> Self {
+        self.track = Some(track.into());
         self
     }
-    pub fn with_late(mut self, late: i32) -> Self {
-        self.late = Some(late);
+    pub fn with_track_change(mut self, track_change: impl Into<Filter<String>>) -> Self {
+        self.track_change = Some(track_change.into());
         self
     }
-    pub fn with_source(mut self, source: String) -> Self {
-        self.source = Some(source);
+    pub fn with_currentstop_id(mut self, currentstop_id: impl Into<Filter<String>>) -> Self {
+        self.currentstop_id = Some(currentstop_id.into());
+        self
+    }
+    pub fn with_nextstop_id(mut self, nextstop_id: impl Into<Filter<String>>) -> Self {
+        self.nextstop_id = Some(nextstop_id.into());
+        self
+    }
+    pub fn with_source_id(mut self, source_id: impl Into<Filter<String>>) -> Self {
+        self.source_id = Some(source_id.into());
+        self
+    }
+    pub fn with_dest_id(mut self, dest_id: impl Into<Filter<String>>) -> Self {
+        self.dest_id = Some(dest_id.into());
         self
     }
     pub fn with_fields<S: Into<String>>(mut self, fields: Vec<S>) -> Self {
         self.fields = Some(fields.into_iter().map(|s| s.into()).collect());
         self
     }
+    /// The fields `build` will select, as client facing names.
+    pub fn selected_fields(&self) -> Vec<String> {
+        self.fields
+            .clone()
+            .unwrap_or_else(|| RECORD_COLUMNS.iter().map(|name| name.to_string()).collect())
+    }
     pub fn build<'b>(self) -> (sqlx::QueryBuilder<'b, sqlx::postgres::Postgres>, bool) {
+        let fields = self.selected_fields();
         let mut builder = sqlx::QueryBuilder::new(format!(
             r#"select
-{}
+  {}
 from
     records
 "#,
-            self.fields
-                .unwrap_or(DEFAULT_RESPONSE_FIELDS.map(|s| s.to_owned()).to_vec())
-                .join(",")
+            select_list(&fields)
         ));
         let mut is_whered = false;
         macro_rules! item {
             ($item:ident) => {
+                item!($item, stringify!($item), |value| value)
+            };
+            ($item:ident, $column:expr, $to_sql:expr) => {
                 if let Some($item) = self.$item {
                     if !is_whered {
                         builder.push(" where ");
@@ -114,23 +359,16 @@ from
                     } else {
                         builder.push(" and ");
                     }
-                    builder.push(format!("{} = ", stringify!($item)));
-                    builder.push_bind($item);
+                    $item.push(&mut builder, $column, $to_sql);
                 }
             };
         }
-        item!(id);
+        item!(id, "records.id", |value| value);
         item!(file_id);
-        if let Some(timestamp) = self.timestamp {
-            if !is_whered {
-                builder.push(" where ");
-                is_whered = true;
-            } else {
-                builder.push(" and ");
-            }
-            builder.push("received_at = ");
-            builder.push_bind(chrono::DateTime::from_timestamp(timestamp, 0));
-        };
+        // `validate` rejects timestamps out of range, so this never binds null.
+        item!(timestamp, "received_at", |timestamp| {
+            chrono::DateTime::from_timestamp(timestamp, 0)
+        });
         item!(trainno);
         item!(service);
         item!(dest);
@@ -140,7 +378,262 @@ from
         item!(consist);
         item!(late);
         item!(source);
+        item!(track);
+        item!(track_change);
+        item!(currentstop_id);
+        item!(nextstop_id);
+        item!(source_id);
+        item!(dest_id);
 
         (builder, is_whered)
     }
+
+    /// Checks every filter only uses the operators its column supports.
+    pub fn validate(&self) -> Result<(), String> {
+        macro_rules! check {
+            ($item:ident, $operators:expr) => {
+                if let Some(ref filter) = self.$item {
+                    filter.validate(stringify!($item), $operators)?;
+                }
+            };
+        }
+        check!(id, Operators::Exact);
+        check!(file_id, Operators::Exact);
+        check!(timestamp, Operators::Range);
+        check!(trainno, Operators::Exact);
+        check!(service, Operators::Exact);
+        check!(dest, Operators::Pattern);
+        check!(currentstop, Operators::Pattern);
+        check!(nextstop, Operators::Exact);
+        check!(line, Operators::Exact);
+        check!(consist, Operators::Pattern);
+        check!(late, Operators::Range);
+        check!(source, Operators::Exact);
+        check!(track, Operators::Exact);
+        check!(track_change, Operators::Exact);
+        check!(currentstop_id, Operators::Exact);
+        check!(nextstop_id, Operators::Exact);
+        check!(source_id, Operators::Exact);
+        check!(dest_id, Operators::Exact);
+
+        if let Some(timestamp) = self.timestamp.as_ref().and_then(|filter| {
+            filter
+                .values()
+                .into_iter()
+                .find(|timestamp| chrono::DateTime::from_timestamp(**timestamp, 0).is_none())
+        }) {
+            return Err(format!("timestamp {timestamp} is out of range"));
+        }
+
+        if let Some(ref fields) = self.fields {
+            if fields.is_empty() {
+                return Err("fields must not be empty".to_string());
+            }
+            if let Some(field) = fields.iter().find(|field| field_type(field).is_none()) {
+                return Err(format!(
+                    "unknown field `{field}`, expected any of: {}",
+                    RECORD_COLUMNS.join(", ")
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        for body in [r#"{"lat3": 1}"#, r#"{"trainNo": "123"}"#] {
+            assert!(
+                serde_json::from_str::<QueryBuilder>(body).is_err(),
+                "{body}"
+            );
+        }
+        assert!(serde_json::from_str::<QueryBuilder>(r#"{"trainno": "123"}"#).is_ok());
+    }
+
+    /// The where clause `body` builds, and how many values it binds.
+    fn where_clause(body: &str) -> (String, usize) {
+        let query: QueryBuilder = serde_json::from_str(body).unwrap();
+        query.validate().unwrap();
+        let (builder, _) = query.build();
+        let sql = builder.sql();
+        let clause = sql[sql.find(" where ").unwrap_or(sql.len())..]
+            .trim()
+            .to_string();
+        let binds = clause.matches('$').count();
+        (clause, binds)
+    }
+
+    #[test]
+    fn values_match_with_eq() {
+        assert_eq!(
+            where_clause(r#"{"trainno": "1001", "late": {"eq": 5}}"#),
+            (
+                "where (true and trainno = $1) and (true and late = $2)".to_string(),
+                2
+            )
+        );
+        assert_eq!(where_clause("{}"), (String::new(), 0));
+    }
+
+    #[test]
+    fn range_operators() {
+        assert_eq!(
+            where_clause(r#"{"late": {"gt": 0, "gte": 1, "lt": 15, "lte": 14}}"#),
+            (
+                "where (true and late > $1 and late >= $2 and late < $3 and late <= $4)"
+                    .to_string(),
+                4
+            )
+        );
+        assert_eq!(
+            where_clause(r#"{"timestamp": {"gte": 1792200000, "lt": 1792203600}}"#),
+            (
+                "where (true and received_at >= $1 and received_at < $2)".to_string(),
+                2
+            )
+        );
+    }
+
+    #[test]
+    fn in_and_patterns() {
+        assert_eq!(
+            where_clause(r#"{"line": {"in": ["Paoli/Thorndale", "Trenton", "Warminster"]}}"#),
+            ("where (true and line in ($1, $2, $3))".to_string(), 3)
+        );
+        assert_eq!(
+            where_clause(r#"{"dest": {"like": "Th%", "ilike": "%DALE"}}"#),
+            (
+                "where (true and dest like $1 and dest ilike $2)".to_string(),
+                2
+            )
+        );
+    }
+
+    #[test]
+    fn nested_not() {
+        assert_eq!(
+            where_clause(r#"{"late": {"gte": 5, "not": {"in": [7, 8], "not": 6}}}"#),
+            (
+                "where (true and late >= $1 and not (true and late in ($2, $3) and not (true and late = $4)))"
+                    .to_string(),
+                4
+            )
+        );
+    }
+
+    #[test]
+    fn unsupported_operators_are_rejected() {
+        let validate = |body: &str| {
+            serde_json::from_str::<QueryBuilder>(body)
+                .unwrap()
+                .validate()
+        };
+        assert_eq!(
+            validate(r#"{"late": {"like": "1%"}}"#),
+            Err("late does not support like or ilike".to_string())
+        );
+        assert_eq!(
+            validate(r#"{"consist": {"gt": "815"}}"#),
+            Err("consist does not support gt, gte, lt or lte".to_string())
+        );
+        assert_eq!(
+            validate(r#"{"trainno": {"in": []}}"#),
+            Err("trainno.in must not be empty".to_string())
+        );
+        // Checked under `not` too.
+        assert_eq!(
+            validate(r#"{"line": {"not": {"ilike": "%"}}}"#),
+            Err("line does not support like or ilike".to_string())
+        );
+        assert!(serde_json::from_str::<QueryBuilder>(r#"{"late": {"between": [1, 2]}}"#).is_err());
+    }
+
+    #[test]
+    fn field_types() {
+        assert!(matches!(field_type("id"), Some(FieldType::Uuid)));
+        assert!(matches!(field_type("trainno"), Some(FieldType::Text)));
+        assert!(matches!(field_type("late"), Some(FieldType::Integer)));
+        assert!(matches!(
+            field_type("received_at"),
+            Some(FieldType::Timestamp)
+        ));
+        // The position and track columns, null for records stored before they were captured.
+        for field in ["lat", "lon", "heading"] {
+            assert!(
+                matches!(field_type(field), Some(FieldType::Real)),
+                "{field}"
+            );
+        }
+        for field in ["track", "track_change", "currentstop_id"] {
+            assert!(
+                matches!(field_type(field), Some(FieldType::Text)),
+                "{field}"
+            );
+        }
+        // `timestamp` is only a filter, the column is `received_at`.
+        for field in ["timestamp", "lat3", "records.id", ""] {
+            assert!(field_type(field).is_none(), "{field}");
+        }
+        assert!(
+            RECORD_COLUMNS
+                .iter()
+                .all(|field| field_type(field).is_some())
+        );
+    }
+
+    #[test]
+    fn selected_fields_are_validated() {
+        let validate = |body: &str| {
+            serde_json::from_str::<QueryBuilder>(body)
+                .unwrap()
+                .validate()
+        };
+        assert!(validate(r#"{"fields": ["trainno", "lat", "received_at"]}"#).is_ok());
+        assert!(
+            validate(r#"{"fields": ["trainno", "speed"]}"#)
+                .unwrap_err()
+                .starts_with("unknown field `speed`")
+        );
+        assert_eq!(
+            validate(r#"{"fields": []}"#),
+            Err("fields must not be empty".to_string())
+        );
+    }
+
+    #[sqlx::test]
+    async fn rows_to_json(pool: sqlx::PgPool) {
+        let row = sqlx::query(
+            "select '1001' as trainno, 3 as late, 39.98::real as lat, null::real as heading,
+            null::text as track, 'epoch'::timestamp + interval '1792200000 seconds' as received_at",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        let fields = [
+            "trainno",
+            "late",
+            "lat",
+            "heading",
+            "track",
+            "received_at",
+            "speed",
+        ]
+        .map(String::from);
+        assert_eq!(
+            serde_json::Value::Object(row_to_json(&row, &fields)),
+            serde_json::json!({
+                "trainno": "1001",
+                "late": 3,
+                "lat": 39.98,
+                "heading": null,
+                "track": null,
+                "received_at": 1_792_200_000,
+            })
+        );
+    }
 }
@@ -0,0 +1,110 @@
+use chrono::{DateTime, Local, Utc};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+use tokio::{fs, sync::mpsc::Sender};
+use uuid::Uuid;
+
+use crate::{SharedAppState, septa::content::Content};
+
+struct ArchivedFile {
+    id: Uuid,
+    path: PathBuf,
+    received_at: DateTime<Utc>,
+}
+
+/// Collects the archived `{uuid}.json` payloads in `dir`, ordered by the `received_at` recorded
+/// for them in `files`. Files without a matching row fall back to their modified time.
+async fn collect_archived_files(
+    state: SharedAppState,
+    dir: &Path,
+) -> anyhow::Result<Vec<ArchivedFile>> {
+    let mut candidates: Vec<(Uuid, PathBuf, Option<DateTime<Utc>>)> = vec![];
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+            continue;
+        }
+        let Some(id) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| Uuid::parse_str(stem).ok())
+        else {
+            warn!("Skipping file without a uuid name: {:?}", path);
+            continue;
+        };
+        let modified = entry
+            .metadata()
+            .await
+            .and_then(|meta| meta.modified())
+            .map(|mtime| DateTime::<Local>::from(mtime).to_utc())
+            .ok();
+        candidates.push((id, path, modified));
+    }
+
+    let ids: Vec<Uuid> = candidates.iter().map(|(id, _, _)| *id).collect();
+    let received: HashMap<Uuid, DateTime<Utc>> =
+        sqlx::query!("SELECT id, received_at FROM files WHERE id = ANY($1)", &ids)
+            .fetch_all(&state.read().await.pg_pool)
+            .await?
+            .into_iter()
+            .map(|row| (row.id, row.received_at.and_utc()))
+            .collect();
+
+    let mut files: Vec<ArchivedFile> = candidates
+        .into_iter()
+        .filter_map(
+            |(id, path, modified)| match received.get(&id).copied().or(modified) {
+                Some(received_at) => Some(ArchivedFile {
+                    id,
+                    path,
+                    received_at,
+                }),
+                None => {
+                    warn!("Skipping file with no known received time: {:?}", path);
+                    None
+                }
+            },
+        )
+        .collect();
+    files.sort_by_key(|file| file.received_at);
+    Ok(files)
+}
+
+/// Reads every archived payload in `dir` and pushes it through the processing channel in
+/// `received_at` order. Returns the number of payloads sent.
+pub async fn replay_directory(
+    state: SharedAppState,
+    dir: PathBuf,
+    sender: Sender<Content>,
+) -> anyhow::Result<usize> {
+    let files = collect_archived_files(state, &dir).await?;
+    info!("Replaying {} archived files from {:?}.", files.len(), dir);
+
+    let mut sent = 0;
+    for file in files {
+        let raw = match fs::read_to_string(&file.path).await {
+            Ok(raw) => raw,
+            Err(e) => {
+                error!("Failed to read archived file: {:?} - {:?}", file.path, e);
+                continue;
+            }
+        };
+        let content = match Content::from_raw(file.received_at, raw) {
+            Ok(content) => Content {
+                replayed_id: Some(file.id),
+                ..content
+            },
+            Err(e) => {
+                error!("Failed to parse archived file: {:?} - {:?}", file.path, e);
+                continue;
+            }
+        };
+        trace!("Replaying {:?} received at {}", file.path, file.received_at);
+        sender.send(content).await?;
+        sent += 1;
+    }
+    Ok(sent)
+}
@@ -0,0 +1,242 @@
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::{collections::HashMap, sync::LazyLock};
+
+/// The stations catalogue, bundled so a deploy always has the names it was built with.
+const STATIONS_JSON: &str = include_str!("../../data/stations.json");
+
+pub static STATIONS: LazyLock<Catalogue> = LazyLock::new(|| {
+    Catalogue::from_json(STATIONS_JSON).expect("Bundled stations catalogue is invalid")
+});
+
+/// The `records` columns holding stop names, and the columns their station ids go in.
+const STOP_COLUMNS: [(&str, &str); 4] = [
+    ("currentstop", "currentstop_id"),
+    ("nextstop", "nextstop_id"),
+    ("source", "source_id"),
+    ("dest", "dest_id"),
+];
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Station {
+    pub id: String,
+    pub name: String,
+    /// Other spellings the feeds use, ex: `30th St` for Gray 30th Street.
+    pub aliases: Vec<String>,
+    pub lines: Vec<String>,
+    pub lat: f32,
+    pub lon: f32,
+}
+
+/// Reduces a stop name to the form names are matched on: lowercase, without punctuation, a
+/// trailing "station" or common abbreviations, so `Norristown T.C.`, `Norristown TC` and
+/// `Norristown Transportation Center` all match.
+pub fn normalise_name(name: &str) -> String {
+    let name = name
+        .to_lowercase()
+        .replace('&', " and ")
+        .replace(['.', '\''], "")
+        .replace(|c: char| !c.is_alphanumeric(), " ");
+    let mut words: Vec<&str> = vec![];
+    for word in name.split_whitespace() {
+        let word = match word {
+            "avenue" => "ave",
+            "street" => "st",
+            "saint" => "st",
+            "junction" => "jct",
+            "mount" => "mt",
+            "center" if words.last() == Some(&"transportation") => {
+                words.pop();
+                "tc"
+            }
+            word => word,
+        };
+        words.push(word);
+    }
+    if words.len() > 1 && words.last() == Some(&"station") {
+        words.pop();
+    }
+    words.join(" ")
+}
+
+/// Every station, and the normalised names and aliases that map to each.
+#[derive(Debug)]
+pub struct Catalogue {
+    stations: Vec<Station>,
+    by_name: HashMap<String, usize>,
+}
+
+impl Catalogue {
+    /// Fails if a name or alias normalises to the same name as another station's.
+    pub fn from_json(json: &str) -> anyhow::Result<Catalogue> {
+        let stations: Vec<Station> = serde_json::from_str(json)?;
+        let mut by_name = HashMap::new();
+        for (i, station) in stations.iter().enumerate() {
+            for name in std::iter::once(&station.name).chain(&station.aliases) {
+                if let Some(other) = by_name.insert(normalise_name(name), i)
+                    && other != i
+                {
+                    anyhow::bail!(
+                        "{name:?} is ambiguous between {} and {}",
+                        stations[other].id,
+                        station.id
+                    );
+                }
+            }
+        }
+        Ok(Catalogue { stations, by_name })
+    }
+
+    pub fn stations(&self) -> &[Station] {
+        &self.stations
+    }
+
+    /// The station a raw stop name refers to, if it's in the catalogue.
+    pub fn lookup(&self, name: &str) -> Option<&Station> {
+        self.by_name
+            .get(&normalise_name(name))
+            .map(|i| &self.stations[*i])
+    }
+
+    pub fn station_id(&self, name: &str) -> Option<String> {
+        self.lookup(name).map(|station| station.id.clone())
+    }
+}
+
+impl Station {
+    /// Makes the `stations` table match the catalogue. Returns the number of stations.
+    pub async fn seed(pool: &PgPool, catalogue: &Catalogue) -> anyhow::Result<usize> {
+        let mut tx = pool.begin().await?;
+        for station in catalogue.stations() {
+            sqlx::query!(
+                r#"insert into stations (id, name, aliases, lines, lat, lon)
+values ($1, $2, $3, $4, $5, $6)
+on conflict (id) do update set
+  name = excluded.name,
+  aliases = excluded.aliases,
+  lines = excluded.lines,
+  lat = excluded.lat,
+  lon = excluded.lon
+"#,
+                station.id,
+                station.name,
+                &station.aliases,
+                &station.lines,
+                station.lat,
+                station.lon
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        let ids: Vec<String> = catalogue.stations().iter().map(|s| s.id.clone()).collect();
+        sqlx::query!("delete from stations where id <> all($1)", &ids)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(catalogue.stations().len())
+    }
+
+    /// Every station, or only those serving `line`, ordered by name.
+    pub async fn fetch_all(pool: &PgPool, line: Option<&str>) -> anyhow::Result<Vec<Station>> {
+        Ok(sqlx::query_as!(
+            Station,
+            r#"select id, name, aliases, lines, lat, lon
+from
+    stations
+where
+  $1::varchar is null or $1 = any(lines)
+order by
+  name
+"#,
+            line
+        )
+        .fetch_all(pool)
+        .await?)
+    }
+
+    pub async fn fetch(pool: &PgPool, id: &str) -> anyhow::Result<Option<Station>> {
+        Ok(sqlx::query_as!(
+            Station,
+            "select id, name, aliases, lines, lat, lon from stations where id = $1",
+            id
+        )
+        .fetch_optional(pool)
+        .await?)
+    }
+}
+
+/// Sets the station id columns of every record from its raw stop names, including ones recorded
+/// before a name was added to the catalogue. Returns the number of ids changed.
+pub async fn normalise_records(pool: &PgPool, catalogue: &Catalogue) -> anyhow::Result<u64> {
+    let mut updated = 0;
+    for (column, id_column) in STOP_COLUMNS {
+        let names: Vec<String> =
+            sqlx::query_scalar(&format!("select distinct {column} from records"))
+                .fetch_all(pool)
+                .await?;
+        let ids: Vec<Option<String>> = names
+            .iter()
+            .map(|name| catalogue.station_id(name))
+            .collect();
+        for (name, id) in names.iter().zip(&ids) {
+            if id.is_none() {
+                warn!("No station matches {column} {name:?}.");
+            }
+        }
+        let result = sqlx::query(&format!(
+            r#"update records
+set
+  {id_column} = names.id
+from
+  unnest($1::varchar[], $2::varchar[]) as names(name, id)
+where
+  records.{column} = names.name
+  and records.{id_column} is distinct from names.id
+"#
+        ))
+        .bind(&names)
+        .bind(&ids)
+        .execute(pool)
+        .await?;
+        info!("Updated {} of {id_column}.", result.rows_affected());
+        updated += result.rows_affected();
+    }
+    Ok(updated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundled_catalogue_parses() {
+        assert!(Catalogue::from_json(STATIONS_JSON).is_ok());
+        assert!(!STATIONS.stations().is_empty());
+    }
+
+    #[test]
+    fn spellings_resolve_to_the_same_station() {
+        let cases = [
+            ("49th St", "49th-street"),
+            ("49th Street", "49th-street"),
+            ("Elm St", "elm-street"),
+            ("Norristown TC", "norristown-tc"),
+            ("Norristown T.C.", "norristown-tc"),
+            ("Norristown Transportation Center", "norristown-tc"),
+            ("Fern Rock TC", "fern-rock-tc"),
+            ("30th Street Station", "gray-30th-street"),
+            ("Gray 30th Street Station", "gray-30th-street"),
+            ("Ardmore Station", "ardmore"),
+        ];
+        for (name, id) in cases {
+            assert_eq!(STATIONS.station_id(name).as_deref(), Some(id), "{name}");
+        }
+    }
+
+    #[test]
+    fn unknown_names_have_no_station() {
+        assert_eq!(STATIONS.station_id("Nowhere Junction"), None);
+        // "Station" is only dropped after another word.
+        assert_eq!(normalise_name("Station"), "station");
+    }
+}
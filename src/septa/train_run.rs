@@ -0,0 +1,340 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use futures::TryStreamExt;
+use serde::Serialize;
+use sqlx::PgPool;
+
+use crate::{
+    config::SeptaConfig,
+    db::QueryOrdering,
+    septa::train_view::{TrainView, select_records},
+};
+
+/// A stretch of time a train spent reporting the same `currentstop`.
+#[derive(Debug, Serialize, Clone)]
+pub struct StopVisit {
+    pub stop: String,
+    #[serde(serialize_with = "crate::serde_utils::serialize_date_time")]
+    pub first_seen_at: DateTime<Utc>,
+    #[serde(serialize_with = "crate::serde_utils::serialize_date_time")]
+    pub last_seen_at: DateTime<Utc>,
+    /// Lateness as of the last record at this stop.
+    pub late: i32,
+}
+
+/// One train number's trip on one service day.
+#[derive(Debug, Serialize, Clone)]
+pub struct TrainRun {
+    pub trainno: String,
+    #[serde(serialize_with = "crate::serde_utils::serialize_date")]
+    pub service_date: NaiveDate,
+    /// Every line reported during the run, in the order they were first seen.
+    pub lines: Vec<String>,
+    pub service: String,
+    pub origin: String,
+    pub destination: String,
+    #[serde(serialize_with = "crate::serde_utils::serialize_date_time")]
+    pub started_at: DateTime<Utc>,
+    #[serde(serialize_with = "crate::serde_utils::serialize_date_time")]
+    pub ended_at: DateTime<Utc>,
+    pub final_late: i32,
+    pub record_count: usize,
+    pub stops: Vec<StopVisit>,
+}
+
+impl TrainRun {
+    /// Builds a run from the records of a single service day, ordered oldest first.
+    pub fn from_records(service_date: NaiveDate, records: &[TrainView]) -> Option<TrainRun> {
+        let first = records.first()?;
+        let last = records.last()?;
+
+        let mut lines: Vec<String> = vec![];
+        let mut stops: Vec<StopVisit> = vec![];
+        for record in records {
+            if !lines.contains(&record.line) {
+                lines.push(record.line.clone());
+            }
+            match stops.last_mut() {
+                Some(visit) if visit.stop == record.currentstop => {
+                    visit.last_seen_at = record.timestamp;
+                    visit.late = record.late;
+                }
+                _ => stops.push(StopVisit {
+                    stop: record.currentstop.clone(),
+                    first_seen_at: record.timestamp,
+                    last_seen_at: record.timestamp,
+                    late: record.late,
+                }),
+            }
+        }
+
+        Some(TrainRun {
+            trainno: first.trainno.clone(),
+            service_date,
+            lines,
+            service: last.service.clone(),
+            origin: first.source.clone(),
+            destination: last.dest.clone(),
+            started_at: first.timestamp,
+            ended_at: last.timestamp,
+            final_late: last.late,
+            record_count: records.len(),
+            stops,
+        })
+    }
+
+    /// Fetches up to `limit` runs of a train. `before` and `after` are widened to whole service
+    /// days so runs aren't cut in half.
+    pub async fn fetch_for_train(
+        pool: PgPool,
+        config: &SeptaConfig,
+        trainno: &str,
+        limit: i64,
+        before: Option<DateTime<Utc>>,
+        after: Option<DateTime<Utc>>,
+        order: Option<QueryOrdering>,
+    ) -> anyhow::Result<Vec<TrainRun>> {
+        let mut builder = sqlx::QueryBuilder::new(select_records());
+        builder.push(" WHERE trainno = ");
+        builder.push_bind(trainno);
+        if let Some(before) = before {
+            let (_, end) = config.service_date_bounds(config.service_date(before));
+            builder.push(" and received_at < ");
+            builder.push_bind(end.naive_utc());
+        }
+        if let Some(after) = after {
+            let (start, _) = config.service_date_bounds(config.service_date(after));
+            builder.push(" and received_at >= ");
+            builder.push_bind(start.naive_utc());
+        }
+        builder.push(format!(
+            " ORDER BY received_at {}",
+            order.unwrap_or(QueryOrdering::DESC)
+        ));
+
+        // Records for a service day are contiguous in either order, so stream them and stop as
+        // soon as the run after the last one we need starts.
+        let mut days: Vec<(NaiveDate, Vec<TrainView>)> = vec![];
+        let mut rows = builder.build().fetch(&pool);
+        while let Some(row) = rows.try_next().await? {
+            let record = TrainView::from_row(&row);
+            let date = config.service_date(record.timestamp);
+            match days.last_mut() {
+                Some((day, records)) if *day == date => records.push(record),
+                _ => {
+                    if days.len() as i64 >= limit {
+                        break;
+                    }
+                    days.push((date, vec![record]));
+                }
+            }
+        }
+
+        Ok(days
+            .into_iter()
+            .filter_map(|(date, mut records)| {
+                records.sort_by_key(|record| record.timestamp);
+                TrainRun::from_records(date, &records)
+            })
+            .collect())
+    }
+
+    /// Streams every run with a service date in `[from, to]` to `on_run`, optionally only for one
+    /// train, without holding more than one run's records in memory.
+    pub async fn for_each_in_range<F: FnMut(TrainRun)>(
+        pool: PgPool,
+        config: &SeptaConfig,
+        from: NaiveDate,
+        to: NaiveDate,
+        trainno: Option<&str>,
+        mut on_run: F,
+    ) -> anyhow::Result<()> {
+        let (start, _) = config.service_date_bounds(from);
+        let (_, end) = config.service_date_bounds(to);
+        let mut builder = sqlx::QueryBuilder::new(select_records());
+        builder.push(" WHERE received_at >= ");
+        builder.push_bind(start.naive_utc());
+        builder.push(" and received_at < ");
+        builder.push_bind(end.naive_utc());
+        if let Some(trainno) = trainno {
+            builder.push(" and trainno = ");
+            builder.push_bind(trainno);
+        }
+        builder.push(" ORDER BY trainno, received_at ASC");
+
+        let mut current: Option<(NaiveDate, Vec<TrainView>)> = None;
+        let mut rows = builder.build().fetch(&pool);
+        while let Some(row) = rows.try_next().await? {
+            let record = TrainView::from_row(&row);
+            let date = config.service_date(record.timestamp);
+            match current {
+                Some((day, ref mut records))
+                    if day == date && records[0].trainno == record.trainno =>
+                {
+                    records.push(record)
+                }
+                _ => {
+                    if let Some((day, records)) = current.replace((date, vec![record])) {
+                        on_run(TrainRun::from_records(day, &records).unwrap());
+                    }
+                }
+            }
+        }
+        if let Some((day, records)) = current {
+            on_run(TrainRun::from_records(day, &records).unwrap());
+        }
+        Ok(())
+    }
+
+    pub async fn fetch_for_date(
+        pool: PgPool,
+        config: &SeptaConfig,
+        trainno: &str,
+        service_date: NaiveDate,
+    ) -> anyhow::Result<Option<TrainRun>> {
+        let records = TrainRun::fetch_records(pool, config, trainno, service_date).await?;
+        Ok(TrainRun::from_records(service_date, &records))
+    }
+
+    /// The records of a train on one service day, oldest first.
+    pub async fn fetch_records(
+        pool: PgPool,
+        config: &SeptaConfig,
+        trainno: &str,
+        service_date: NaiveDate,
+    ) -> anyhow::Result<Vec<TrainView>> {
+        let (start, end) = config.service_date_bounds(service_date);
+        let mut builder = sqlx::QueryBuilder::new(select_records());
+        builder.push(" WHERE trainno = ");
+        builder.push_bind(trainno);
+        builder.push(" and received_at >= ");
+        builder.push_bind(start.naive_utc());
+        builder.push(" and received_at < ");
+        builder.push_bind(end.naive_utc());
+        builder.push(" ORDER BY received_at ASC");
+
+        let records: Vec<TrainView> = builder
+            .build()
+            .fetch_all(&pool)
+            .await?
+            .iter()
+            .map(TrainView::from_row)
+            .collect();
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use chrono::{Local, NaiveTime, TimeZone};
+
+    /// A record of train 1001 at `local_time` on 2026-10-14.
+    fn record(local_time: &str, stop: &str, line: &str, late: i32) -> TrainView {
+        let mut record: TrainView = serde_json::from_value(serde_json::json!({
+            "trainno": "1001",
+            "service": "LOCAL",
+            "dest": "Thorndale",
+            "currentstop": stop,
+            "nextstop": "",
+            "line": line,
+            "consist": "815,816",
+            "late": late,
+            "SOURCE": "30th Street Station",
+        }))
+        .unwrap();
+        let (day, time) = local_time.split_once(' ').unwrap_or(("14", local_time));
+        let date = NaiveDate::from_ymd_opt(2026, 10, day.parse().unwrap()).unwrap();
+        let time = NaiveTime::parse_from_str(time, "%H:%M").unwrap();
+        record.timestamp = Local
+            .from_local_datetime(&date.and_time(time))
+            .unwrap()
+            .to_utc();
+        record
+    }
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2026, 10, day).unwrap()
+    }
+
+    #[test]
+    fn stops_lines_and_lateness() {
+        let mut records = vec![
+            record("06:00", "30th Street Station", "Paoli/Thorndale", 0),
+            record("06:10", "Overbrook", "Paoli/Thorndale", 0),
+            record("06:12", "Overbrook", "Paoli/Thorndale", 1),
+            record("06:20", "Ardmore", "Cynwyd", 2),
+            record("06:30", "Ardmore", "Paoli/Thorndale", 3),
+        ];
+        records[4].dest = "Malvern".to_string();
+        let run = TrainRun::from_records(date(14), &records).unwrap();
+
+        let stops: Vec<(&str, String, String, i32)> = run
+            .stops
+            .iter()
+            .map(|visit| {
+                (
+                    visit.stop.as_str(),
+                    visit
+                        .first_seen_at
+                        .with_timezone(&Local)
+                        .format("%H:%M")
+                        .to_string(),
+                    visit
+                        .last_seen_at
+                        .with_timezone(&Local)
+                        .format("%H:%M")
+                        .to_string(),
+                    visit.late,
+                )
+            })
+            .collect();
+        let visit =
+            |stop, first: &str, last: &str, late| (stop, first.to_string(), last.to_string(), late);
+        assert_eq!(
+            stops,
+            vec![
+                visit("30th Street Station", "06:00", "06:00", 0),
+                visit("Overbrook", "06:10", "06:12", 1),
+                visit("Ardmore", "06:20", "06:30", 3),
+            ]
+        );
+        assert_eq!(run.lines, vec!["Paoli/Thorndale", "Cynwyd"]);
+        assert_eq!(run.origin, "30th Street Station");
+        assert_eq!(run.destination, "Malvern");
+        assert_eq!(run.final_late, 3);
+        assert_eq!(run.record_count, 5);
+        assert_eq!(
+            (run.started_at, run.ended_at),
+            (records[0].timestamp, records[4].timestamp)
+        );
+    }
+
+    #[test]
+    fn no_records_no_run() {
+        assert!(TrainRun::from_records(date(14), &[]).is_none());
+    }
+
+    #[test]
+    fn records_split_at_the_service_day_cutoff() {
+        let config = Config::default().septa;
+        let records = [
+            record("14 23:40", "Ardmore", "Paoli/Thorndale", 0),
+            record("15 01:55", "Paoli", "Paoli/Thorndale", 4),
+            record("15 02:05", "30th Street Station", "Paoli/Thorndale", 0),
+        ];
+        let dates: Vec<NaiveDate> = records
+            .iter()
+            .map(|record| config.service_date(record.timestamp))
+            .collect();
+        // Running past midnight still counts towards the day before, up to the 2 AM cutoff.
+        assert_eq!(dates, vec![date(14), date(14), date(15)]);
+
+        let late_night = TrainRun::from_records(date(14), &records[..2]).unwrap();
+        assert_eq!(late_night.final_late, 4);
+        assert_eq!(late_night.stops.len(), 2);
+        let (start, end) = config.service_date_bounds(date(14));
+        assert!(start <= late_night.started_at && late_night.ended_at < end);
+        assert!(records[2].timestamp >= end);
+    }
+}
@@ -1,17 +1,65 @@
-use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use futures::TryStreamExt;
 use serde::{Deserialize, Serialize};
-use sqlx::{Database, PgPool, Row, query_builder};
+use sqlx::{Database, PgPool, Postgres, Row, Transaction, postgres::PgRow, query_builder};
+use tokio::sync::mpsc;
 use uuid::Uuid;
 
 use crate::{
     db::{
         QueryOrdering,
+        cursor::{Page, Pagination},
         tracking::{Changed, Value},
     },
-    septa::content::File,
+    septa::{consist::parse_consist, content::File, stations::Catalogue},
 };
 
-#[derive(Debug, Serialize, Deserialize, Clone, Eq)]
+/// The `records` columns `TrainView::from_row` reads, in the order the api returns them.
+pub const RECORD_COLUMNS: [&str; 21] = [
+    "id",
+    "file_id",
+    "trainno",
+    "service",
+    "dest",
+    "currentstop",
+    "nextstop",
+    "line",
+    "consist",
+    "late",
+    "source",
+    "received_at",
+    "lat",
+    "lon",
+    "heading",
+    "track",
+    "track_change",
+    "currentstop_id",
+    "nextstop_id",
+    "source_id",
+    "dest_id",
+];
+
+/// `columns` as a select list, with `id` qualified so it stays unambiguous next to joined tables.
+pub fn select_list<S: AsRef<str>>(columns: &[S]) -> String {
+    columns
+        .iter()
+        .map(|column| match column.as_ref() {
+            "id" => "records.id",
+            column => column,
+        })
+        .collect::<Vec<&str>>()
+        .join(",\n  ")
+}
+
+/// Selects `RECORD_COLUMNS` from `records`, for queries to add their conditions to.
+pub fn select_records() -> String {
+    format!(
+        "select\n  {}\nfrom\n    records\n",
+        select_list(&RECORD_COLUMNS)
+    )
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TrainView {
     #[serde(skip_deserializing, default = "Uuid::new_v4")]
     pub id: Uuid,
@@ -23,10 +71,16 @@ pub struct TrainView {
         serialize_with = "crate::serde_utils::serialize_date_time"
     )]
     pub timestamp: chrono::DateTime<Utc>,
-    // #[serde(deserialize_with = "crate::serde_utils::deserialize_f32_string")]
-    // pub lat: f32,
-    // #[serde(deserialize_with = "crate::serde_utils::deserialize_f32_string")]
-    // pub lon: f32,
+    #[serde(
+        default,
+        deserialize_with = "crate::serde_utils::deserialize_opt_f32_string"
+    )]
+    pub lat: Option<f32>,
+    #[serde(
+        default,
+        deserialize_with = "crate::serde_utils::deserialize_opt_f32_string"
+    )]
+    pub lon: Option<f32>,
     pub trainno: String,
     pub service: String,
     pub dest: String,
@@ -34,17 +88,49 @@ pub struct TrainView {
     pub nextstop: String,
     pub line: String,
     pub consist: String,
-    // #[serde(deserialize_with = "crate::serde_utils::deserialize_opt_f32_string")]
-    // pub heading: Option<f32>,
+    #[serde(
+        default,
+        deserialize_with = "crate::serde_utils::deserialize_opt_f32_string"
+    )]
+    pub heading: Option<f32>,
     pub late: i32,
     #[serde(rename = "SOURCE")]
     pub source: String,
-    // #[serde(rename = "TRACK")]
-    // pub track: String,
-    // #[serde(rename = "TRACK_CHANGE")]
-    // pub track_change: String,
+    #[serde(
+        rename = "TRACK",
+        default,
+        deserialize_with = "crate::serde_utils::deserialize_null_string"
+    )]
+    pub track: String,
+    #[serde(
+        rename = "TRACK_CHANGE",
+        default,
+        deserialize_with = "crate::serde_utils::deserialize_null_string"
+    )]
+    pub track_change: String,
+    /// Station ids for the stop names, null when a name isn't in the stations catalogue. Set
+    /// from the names, so they're left out of comparisons.
+    #[serde(skip_deserializing, default)]
+    pub currentstop_id: Option<String>,
+    #[serde(skip_deserializing, default)]
+    pub nextstop_id: Option<String>,
+    #[serde(skip_deserializing, default)]
+    pub source_id: Option<String>,
+    #[serde(skip_deserializing, default)]
+    pub dest_id: Option<String>,
+    /// The latest poll the train was seen in, which its position is as of. Only tracked in
+    /// memory, so null for stored records, whose position is as of `timestamp`.
+    #[serde(
+        skip_deserializing,
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "crate::serde_utils::serialize_opt_date_time"
+    )]
+    pub seen_at: Option<chrono::DateTime<Utc>>,
 }
 
+/// Position (`lat`, `lon`, `heading`) is left out on purpose: it moves on nearly every poll, and
+/// comparing it would write a new record for every train every time.
 impl PartialEq for TrainView {
     fn eq(&self, other: &Self) -> bool {
         self.trainno == other.trainno
@@ -56,8 +142,19 @@ impl PartialEq for TrainView {
             && self.consist == other.consist
             && self.late == other.late
             && self.source == other.source
+            && self.track == other.track
+            && self.track_change == other.track_change
     }
 }
+impl Eq for TrainView {}
+
+/// A train's newest view and what changed from the one before it, as published to live
+/// subscribers.
+#[derive(Debug, Serialize, Clone)]
+pub struct TrainUpdate {
+    pub train: TrainView,
+    pub changes: Vec<Changed>,
+}
 
 macro_rules! evaluate_changes {
     ($si:ident, $self:ident, $prev:ident, $type:ident, $store:ident) => {
@@ -81,6 +178,14 @@ macro_rules! evaluate_changes {
 }
 
 impl TrainView {
+    /// Sets the station ids from the stop names.
+    pub fn normalise_stations(&mut self, catalogue: &Catalogue) {
+        self.currentstop_id = catalogue.station_id(&self.currentstop);
+        self.nextstop_id = catalogue.station_id(&self.nextstop);
+        self.source_id = catalogue.station_id(&self.source);
+        self.dest_id = catalogue.station_id(&self.dest);
+    }
+
     pub fn get_changes(&self, prev: &TrainView) -> Option<Vec<Changed>> {
         let mut changed = vec![];
         if self.trainno != prev.trainno {
@@ -97,102 +202,104 @@ impl TrainView {
                 (nextstop, String),
                 (line, String),
                 (consist, String),
-                (source, String)
+                (source, String),
+                (track, String),
+                (track_change, String)
             ],
             self,
             prev,
             changed
         );
 
-        if changed.len() > 0 {
+        if !changed.is_empty() {
             Some(changed)
         } else {
             None
         }
     }
 
+    /// When the train was last seen: the latest poll if it's tracked, else when it was recorded.
+    pub fn last_seen(&self) -> chrono::DateTime<Utc> {
+        self.seen_at.unwrap_or(self.timestamp)
+    }
+
+    /// Maps a row selecting the columns of `records` (with `received_at` as the timestamp).
+    pub fn from_row(row: &PgRow) -> TrainView {
+        TrainView {
+            id: row.get("id"),
+            file_id: row.get("file_id"),
+            timestamp: row.get::<NaiveDateTime, &str>("received_at").and_utc(),
+            trainno: row.get("trainno"),
+            service: row.get("service"),
+            dest: row.get("dest"),
+            currentstop: row.get("currentstop"),
+            nextstop: row.get("nextstop"),
+            line: row.get("line"),
+            consist: row.get("consist"),
+            late: row.get("late"),
+            source: row.get("source"),
+            lat: row.get("lat"),
+            lon: row.get("lon"),
+            heading: row.get("heading"),
+            track: row.get::<Option<String>, &str>("track").unwrap_or_default(),
+            track_change: row
+                .get::<Option<String>, &str>("track_change")
+                .unwrap_or_default(),
+            currentstop_id: row.get("currentstop_id"),
+            nextstop_id: row.get("nextstop_id"),
+            source_id: row.get("source_id"),
+            dest_id: row.get("dest_id"),
+            seen_at: None,
+        }
+    }
+
     /// Database
-    pub async fn get_most_recent_all(pool: PgPool) -> anyhow::Result<Vec<TrainView>> {
-        let yesterday = chrono::Local::now() - Duration::days(1);
-        let two_am_yesterday = yesterday
-            .with_time(chrono::NaiveTime::from_hms_opt(2, 0, 0).unwrap())
-            .unwrap()
-            .to_utc();
-        let records = sqlx::query!(
-            r"
-select 
-  distinct on (trainno) 
-  records.id,
-  file_id,
-  trainno,
-  service,
-  dest,
-  currentstop,
-  nextstop,
-  line,
-  consist,
-  late,
-  source,
-  received_at
-from 
-     records 
+    pub async fn get_most_recent_all(
+        pool: PgPool,
+        since: DateTime<Utc>,
+    ) -> anyhow::Result<Vec<TrainView>> {
+        TrainView::get_snapshot(pool, None, Some(since)).await
+    }
+
+    /// The latest record of every train as of `at` (or now), leaving out trains with no record
+    /// since `since`.
+    pub async fn get_snapshot(
+        pool: PgPool,
+        at: Option<DateTime<Utc>>,
+        since: Option<DateTime<Utc>>,
+    ) -> anyhow::Result<Vec<TrainView>> {
+        let records = sqlx::query(&format!(
+            r"select
+  distinct on (trainno)
+  {}
+from
+    records
 where
-  received_at > $1
-order by 
-    trainno, 
+  ($1::timestamp is null or received_at <= $1)
+  and ($2::timestamp is null or received_at >= $2)
+order by
+    trainno,
     received_at desc
 ",
-            two_am_yesterday.naive_utc()
-        )
+            select_list(&RECORD_COLUMNS)
+        ))
+        .bind(at.map(|at| at.naive_utc()))
+        .bind(since.map(|since| since.naive_utc()))
         .fetch_all(&pool)
         .await?
         .iter()
-        .map(|row| TrainView {
-            id: row.id,
-            file_id: row.file_id,
-            timestamp: row
-                .received_at
-                .and_then(|r| Some(r.and_utc()))
-                .unwrap_or_default(),
-            trainno: row.trainno.clone(),
-            service: row.service.clone(),
-            dest: row.dest.clone(),
-            currentstop: row.currentstop.clone(),
-            nextstop: row.nextstop.clone(),
-            line: row.line.clone(),
-            consist: row.consist.clone(),
-            late: row.late.clone(),
-            source: row.source.clone(),
-        })
+        .map(TrainView::from_row)
         .collect();
         Ok(records)
     }
     pub async fn fetch_for_train(
         pool: PgPool,
         trainno: &str,
-        limit: Option<i64>,
+        pagination: Pagination,
         before: Option<DateTime<Utc>>,
         after: Option<DateTime<Utc>>,
-        order: Option<QueryOrdering>,
-    ) -> anyhow::Result<Vec<TrainView>> {
-        let mut builder = query_builder::QueryBuilder::new(
-            r#"select
-  records.id,
-  file_id,
-  trainno,
-  service,
-  dest,
-  currentstop,
-  nextstop,
-  line,
-  consist,
-  late,
-  source,
-  received_at
-from
-    records
-"#,
-        );
+    ) -> anyhow::Result<Page<TrainView>> {
+        let mut builder = query_builder::QueryBuilder::new(select_records());
         fn where_helper<'args, T, DB: Database>(
             field: &str,
             comparator: &str,
@@ -214,63 +321,35 @@ from
         if let Some(after) = after {
             where_helper("received_at", ">", after, &mut builder);
         }
-
-        // TODO: This is unsafe, but it's the best way to do it since it's an enum, and we
-        // alredy throw an error if it's the incorrect of the two options anywa...
-        builder.push(format!(
-            " ORDER BY received_at {}",
-            order.unwrap_or(QueryOrdering::DESC)
-        ));
-        builder.push(" LIMIT ");
-        builder.push_bind(enforce_limit_bounds(limit));
+        pagination.push_condition(&mut builder, " and ");
+        pagination.push_order_and_limit(&mut builder);
 
         let results = builder.build();
         let results = results.fetch_all(&pool).await?;
 
-        let records: Vec<TrainView> = results
-            .iter()
-            .map(|row| TrainView {
-                id: row.get("id"),
-                file_id: row.get("file_id"),
-                timestamp: row.get::<NaiveDateTime, &str>("received_at").and_utc(),
-                trainno: row.get("trainno"),
-                service: row.get("service"),
-                dest: row.get("dest"),
-                currentstop: row.get("currentstop"),
-                nextstop: row.get("nextstop"),
-                line: row.get("line"),
-                consist: row.get("consist"),
-                late: row.get("late"),
-                source: row.get("source"),
-            })
-            .collect();
-        Ok(records)
+        let records: Vec<TrainView> = results.iter().map(TrainView::from_row).collect();
+        Ok(pagination.page(records, |record| (record.timestamp, record.id)))
     }
 
-    pub async fn query_trains(
-        pool: PgPool,
+    /// Builds `query` with the time bounds and pagination shared by the query endpoints.
+    fn build_query<'b>(
         query: super::query_builder::QueryBuilder,
-        limit: Option<i64>,
+        pagination: Pagination,
         before: Option<DateTime<Utc>>,
         after: Option<DateTime<Utc>>,
-        order: Option<QueryOrdering>,
-    ) -> anyhow::Result<Vec<TrainView>> {
-        // TOOD: Need to specify this because the return type is not dynamic.
-        // this is important. Also we need to sanitize this as well when we make it dynamic later
-        let query = query.with_fields(vec![
-            "records.id",
-            "file_id",
-            "trainno",
-            "service",
-            "dest",
-            "currentstop",
-            "nextstop",
-            "line",
-            "consist",
-            "late",
-            "source",
-            "received_at",
-        ]);
+    ) -> sqlx::QueryBuilder<'b, sqlx::Postgres> {
+        let (mut builder, where_added) = Self::build_filtered(query, before, after);
+        pagination.push_condition(&mut builder, if where_added { " and " } else { " where " });
+        pagination.push_order_and_limit(&mut builder);
+        builder
+    }
+
+    /// Builds `query` with the time bounds, returning whether a `where` has been added.
+    fn build_filtered<'b>(
+        query: super::query_builder::QueryBuilder,
+        before: Option<DateTime<Utc>>,
+        after: Option<DateTime<Utc>>,
+    ) -> (sqlx::QueryBuilder<'b, sqlx::Postgres>, bool) {
         let (mut builder, mut where_added) = query.build();
 
         if let Some(before) = before {
@@ -286,52 +365,112 @@ from
         if let Some(after) = after {
             if !where_added {
                 builder.push(" where ");
-                let _where_added = true;
+                where_added = true;
             } else {
                 builder.push(" and ");
             }
             builder.push(" received_at > ");
             builder.push_bind(after);
         }
+        (builder, where_added)
+    }
 
-        builder.push(format!(
-            " ORDER BY received_at {}",
-            order.unwrap_or(QueryOrdering::DESC)
-        ));
-        builder.push(" LIMIT ");
-
-        builder.push_bind(enforce_limit_bounds(limit));
+    /// Runs `query` for whole records, ignoring any selected fields.
+    pub async fn query_trains(
+        pool: PgPool,
+        query: super::query_builder::QueryBuilder,
+        pagination: Pagination,
+        before: Option<DateTime<Utc>>,
+        after: Option<DateTime<Utc>>,
+    ) -> anyhow::Result<Page<TrainView>> {
+        let query = super::query_builder::QueryBuilder {
+            fields: None,
+            ..query
+        };
+        let mut builder = Self::build_query(query, pagination, before, after);
 
         let results = builder.build();
         let results = results.fetch_all(&pool).await?;
 
-        let records: Vec<TrainView> = results
-            .iter()
-            .map(|row| TrainView {
-                id: row.get("id"),
-                file_id: row.get("file_id"),
-                timestamp: row.get::<NaiveDateTime, &str>("received_at").and_utc(),
-                trainno: row.get("trainno"),
-                service: row.get("service"),
-                dest: row.get("dest"),
-                currentstop: row.get("currentstop"),
-                nextstop: row.get("nextstop"),
-                line: row.get("line"),
-                consist: row.get("consist"),
-                late: row.get("late"),
-                source: row.get("source"),
-            })
-            .collect();
-        Ok(records)
+        let records: Vec<TrainView> = results.iter().map(TrainView::from_row).collect();
+        Ok(pagination.page(records, |record| (record.timestamp, record.id)))
+    }
+
+    /// Runs `query` for only its selected fields (which must already be validated), as JSON
+    /// objects keyed by column name.
+    pub async fn query_fields(
+        pool: PgPool,
+        query: super::query_builder::QueryBuilder,
+        pagination: Pagination,
+        before: Option<DateTime<Utc>>,
+        after: Option<DateTime<Utc>>,
+    ) -> anyhow::Result<Page<serde_json::Map<String, serde_json::Value>>> {
+        let fields = query.fields.clone().unwrap_or_default();
+        // The cursors need the key of every row, whether or not it was selected.
+        let query = query.with_fields(
+            fields
+                .iter()
+                .map(String::as_str)
+                .chain([
+                    "records.id as cursor_id",
+                    "received_at as cursor_received_at",
+                ])
+                .collect(),
+        );
+        let mut builder = Self::build_query(query, pagination, before, after);
+
+        let results = builder.build().fetch_all(&pool).await?;
+        let page = pagination.page(results, |row| {
+            (
+                row.get::<NaiveDateTime, &str>("cursor_received_at")
+                    .and_utc(),
+                row.get("cursor_id"),
+            )
+        });
+        Ok(Page {
+            items: page
+                .items
+                .iter()
+                .map(|row| super::query_builder::row_to_json(row, &fields))
+                .collect(),
+            next_cursor: page.next_cursor,
+            prev_cursor: page.prev_cursor,
+        })
+    }
+
+    /// Streams every record matching `query` into `sender` as JSON objects of its selected
+    /// fields (all of them when not set), without a limit. Stops early once the receiver is
+    /// dropped.
+    pub async fn export_fields(
+        pool: PgPool,
+        query: super::query_builder::QueryBuilder,
+        before: Option<DateTime<Utc>>,
+        after: Option<DateTime<Utc>>,
+        order: QueryOrdering,
+        sender: mpsc::Sender<anyhow::Result<serde_json::Map<String, serde_json::Value>>>,
+    ) -> anyhow::Result<()> {
+        let fields = query.selected_fields();
+        let query = query.with_fields(fields.clone());
+        let (mut builder, _) = Self::build_filtered(query, before, after);
+        builder.push(format!(" ORDER BY received_at {order}, records.id {order}"));
+
+        let mut rows = builder.build().fetch(&pool);
+        while let Some(row) = rows.try_next().await? {
+            let record = super::query_builder::row_to_json(&row, &fields);
+            if sender.send(Ok(record)).await.is_err() {
+                break;
+            }
+        }
+        Ok(())
     }
 
     #[allow(unused)]
     pub async fn commit_new_record(&self, file: &File, pg_pool: PgPool) -> anyhow::Result<()> {
         sqlx::query!(
             r" INSERT INTO records 
-    (id, file_id, received_at, trainno, service, dest, currentstop, nextstop, line, consist, late, source)
+    (id, file_id, received_at, trainno, service, dest, currentstop, nextstop, line, consist, late, source, lat, lon, heading, track, track_change, currentstop_id, nextstop_id, source_id, dest_id)
 VALUES
-    ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)",
+    ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20, $21)",
             self.id,
             file.id,
             file.received_at.naive_utc(),
@@ -344,6 +483,15 @@ VALUES
             self.consist,
             self.late,
             self.source,
+            self.lat,
+            self.lon,
+            self.heading,
+            self.track,
+            self.track_change,
+            self.currentstop_id,
+            self.nextstop_id,
+            self.source_id,
+            self.dest_id,
         )
         .execute(&pg_pool)
         .await?;
@@ -351,17 +499,17 @@ VALUES
     }
 
     pub async fn commit_new_records(
-        records: &Vec<TrainView>,
+        records: &[TrainView],
         file: &File,
-        pg_pool: PgPool,
+        tx: &mut Transaction<'_, Postgres>,
     ) -> anyhow::Result<u64> {
         let mut builder = sqlx::QueryBuilder::new(
             r" INSERT INTO records 
-    (id, file_id, received_at, trainno, service, dest, currentstop, nextstop, line, consist, late, source) ",
+    (id, file_id, received_at, trainno, service, dest, currentstop, nextstop, line, consist, late, source, lat, lon, heading, track, track_change, currentstop_id, nextstop_id, source_id, dest_id) ",
         );
         builder.push_values(records.iter(), |mut a, record| {
-            a.push_bind(&record.id)
-                .push_bind(&file.id)
+            a.push_bind(record.id)
+                .push_bind(file.id)
                 .push_bind(file.received_at.naive_utc())
                 .push_bind(&record.trainno)
                 .push_bind(&record.service)
@@ -370,22 +518,43 @@ VALUES
                 .push_bind(&record.nextstop)
                 .push_bind(&record.line)
                 .push_bind(&record.consist)
-                .push_bind(&record.late)
-                .push_bind(&record.source);
+                .push_bind(record.late)
+                .push_bind(&record.source)
+                .push_bind(record.lat)
+                .push_bind(record.lon)
+                .push_bind(record.heading)
+                .push_bind(&record.track)
+                .push_bind(&record.track_change)
+                .push_bind(&record.currentstop_id)
+                .push_bind(&record.nextstop_id)
+                .push_bind(&record.source_id)
+                .push_bind(&record.dest_id);
         });
-        let inserted = builder.build().execute(&pg_pool).await?;
-        Ok(inserted.rows_affected())
-    }
-}
 
-/// Enforces the following restriction on passed limit option: `[1, 300]`
-pub fn enforce_limit_bounds(limit: Option<i64>) -> i64 {
-    let limit = limit.unwrap_or(100);
-    if limit > 300 {
-        300
-    } else if limit < 1 {
-        1
-    } else {
-        limit
+        let inserted = builder.build().execute(&mut **tx).await?;
+
+        let cars: Vec<(Uuid, i16, i32)> = records
+            .iter()
+            .flat_map(|record| {
+                // `parse_consist` keeps car numbers to the digits that fit `car_number`.
+                parse_consist(&record.consist)
+                    .into_iter()
+                    .map(|car| car as i32)
+                    .enumerate()
+                    .map(|(i, car)| (record.id, i as i16 + 1, car))
+            })
+            .collect();
+        if !cars.is_empty() {
+            let mut builder = sqlx::QueryBuilder::new(
+                " INSERT INTO record_cars (record_id, position, car_number) ",
+            );
+            builder.push_values(cars, |mut a, (record_id, position, car_number)| {
+                a.push_bind(record_id)
+                    .push_bind(position)
+                    .push_bind(car_number);
+            });
+            builder.build().execute(&mut **tx).await?;
+        }
+        Ok(inserted.rows_affected())
     }
 }
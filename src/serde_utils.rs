@@ -1,18 +1,25 @@
 use chrono::TimeZone;
 use serde::Deserialize;
 
-#[allow(unused)]
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum F32OrString {
+    F32(f32),
+    String(String),
+}
+
+/// Septa isn't consistent about these, so accepts a number, a numeric string, an empty string
+/// or null.
 pub fn deserialize_opt_f32_string<'de, D>(deserializer: D) -> Result<Option<f32>, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
-    let s: String = Deserialize::deserialize(deserializer)?;
-    if s.is_empty() {
-        Ok(None)
-    } else {
-        match s.parse::<f32>().map_err(serde::de::Error::custom) {
-            Ok(r) => Ok(Some(r)),
-            Err(e) => Err(e),
+    match Option::<F32OrString>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(F32OrString::F32(r)) => Ok(Some(r)),
+        Some(F32OrString::String(s)) if s.is_empty() => Ok(None),
+        Some(F32OrString::String(s)) => {
+            s.parse::<f32>().map(Some).map_err(serde::de::Error::custom)
         }
     }
 }
@@ -26,6 +33,14 @@ where
     s.parse::<f32>().map_err(serde::de::Error::custom)
 }
 
+/// Treats a null string the same as an empty one.
+pub fn deserialize_null_string<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(Option::<String>::deserialize(deserializer)?.unwrap_or_default())
+}
+
 pub fn serialize_date_time<S, Tz: TimeZone>(
     val: &chrono::DateTime<Tz>,
     serializer: S,
@@ -35,3 +50,67 @@ where
 {
     serializer.serialize_i64(val.timestamp())
 }
+
+pub fn serialize_opt_date_time<S, Tz: TimeZone>(
+    val: &Option<chrono::DateTime<Tz>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match val {
+        Some(val) => serializer.serialize_some(&val.timestamp()),
+        None => serializer.serialize_none(),
+    }
+}
+
+pub fn serialize_date<S>(val: &chrono::NaiveDate, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.collect_str(&val.format("%Y-%m-%d"))
+}
+
+/// Deserializes optional unix seconds, rejecting any a `DateTime` can't hold rather than
+/// dropping them, so a bad bound doesn't turn into no bound.
+pub fn deserialize_opt_timestamp<'de, D>(
+    deserializer: D,
+) -> Result<Option<chrono::DateTime<chrono::Utc>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match Option::<i64>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(ts) => chrono::DateTime::from_timestamp(ts, 0)
+            .map(Some)
+            .ok_or_else(|| serde::de::Error::custom(format!("timestamp {ts} is out of range"))),
+    }
+}
+
+/// Deserializes an optional `YYYY-MM-DD` date.
+pub fn deserialize_opt_date<'de, D>(deserializer: D) -> Result<Option<chrono::NaiveDate>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(s) => chrono::NaiveDate::parse_from_str(&s, "%Y-%m-%d")
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+    }
+}
+
+/// Deserializes an optional comma separated list of integers, ex: `5,10,15`.
+pub fn deserialize_opt_i32_list<'de, D>(deserializer: D) -> Result<Option<Vec<i32>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(s) => s
+            .split(',')
+            .map(|v| v.trim().parse::<i32>().map_err(serde::de::Error::custom))
+            .collect::<Result<Vec<i32>, D::Error>>()
+            .map(Some),
+    }
+}
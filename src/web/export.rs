@@ -0,0 +1,300 @@
+use actix_web::{
+    HttpRequest, HttpResponse, Responder,
+    http::{StatusCode, header},
+    web::{self, Bytes, Json},
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use futures::{StreamExt, stream};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::sync::{Mutex, mpsc};
+
+use crate::{
+    SharedAppState,
+    analytics::parquet::{self, ExportedDay},
+    db::QueryOrdering,
+    septa::{query_builder::QueryBuilder, train_view::TrainView},
+};
+
+/// Rows buffered between the query and a slow client.
+const EXPORT_BUFFER_ROWS: usize = 256;
+/// The most service days one request can export to Parquet. Longer ranges go through the
+/// `export-parquet` command.
+const MAX_PARQUET_EXPORT_DAYS: i64 = 7;
+/// Held while a request exports to Parquet, so only one runs at a time.
+static PARQUET_EXPORT: Mutex<()> = Mutex::const_new(());
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Csv,
+    Ndjson,
+}
+
+impl ExportFormat {
+    /// `format` wins, then the `Accept` header, then NDJSON.
+    fn negotiate(format: Option<ExportFormat>, req: &HttpRequest) -> ExportFormat {
+        if let Some(format) = format {
+            return format;
+        }
+        let accept = req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        if accept.contains("text/csv") {
+            ExportFormat::Csv
+        } else {
+            ExportFormat::Ndjson
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+        }
+    }
+}
+
+fn csv_line<I: IntoIterator<Item = String>>(values: I) -> Bytes {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    // Writing to a Vec can't fail.
+    let _ = writer.write_record(values);
+    Bytes::from(writer.into_inner().unwrap_or_default())
+}
+
+fn csv_value(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Null => String::new(),
+        serde_json::Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    #[serde(
+        default,
+        deserialize_with = "crate::serde_utils::deserialize_opt_timestamp"
+    )]
+    before: Option<DateTime<Utc>>,
+    #[serde(
+        default,
+        deserialize_with = "crate::serde_utils::deserialize_opt_timestamp"
+    )]
+    after: Option<DateTime<Utc>>,
+    order: Option<QueryOrdering>,
+    format: Option<ExportFormat>,
+}
+
+/// Streams every record matching the `/api/query` body, as CSV or NDJSON.
+pub async fn export_records(
+    req: HttpRequest,
+    body: Bytes,
+    query: web::Query<ExportQuery>,
+    data: web::Data<SharedAppState>,
+) -> impl Responder {
+    #[derive(Serialize)]
+    struct Response {
+        error: String,
+    }
+    let body = match serde_json::from_slice::<QueryBuilder>(&body) {
+        Ok(body) => body,
+        Err(err) => {
+            return HttpResponse::BadRequest().json(Response {
+                error: format!("Error decoding body: {:?}", err),
+            });
+        }
+    };
+    if let Err(error) = body.validate() {
+        return HttpResponse::BadRequest().json(Response { error });
+    }
+
+    let format = ExportFormat::negotiate(query.format, &req);
+    let fields = body.selected_fields();
+    let pg_pool = data.read().await.pg_pool.clone();
+    let (sender, receiver) = mpsc::channel(EXPORT_BUFFER_ROWS);
+    let before = query.before;
+    let after = query.after;
+    let order = query.order.unwrap_or(QueryOrdering::ASC);
+    tokio::spawn(async move {
+        let result =
+            TrainView::export_fields(pg_pool, body, before, after, order, sender.clone()).await;
+        if let Err(e) = result {
+            error!("Error exporting records: {e}");
+            let _ = sender.send(Err(e)).await;
+        }
+    });
+
+    let header = match format {
+        ExportFormat::Csv => Some(csv_line(fields.clone())),
+        ExportFormat::Ndjson => None,
+    };
+    let rows = stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|row| (row, receiver))
+    })
+    .map(move |row| {
+        // An error part way through can't change the status any more, so cut the response
+        // short instead of letting it look complete.
+        let row = row.map_err(actix_web::error::ErrorInternalServerError)?;
+        Ok::<_, actix_web::Error>(match format {
+            ExportFormat::Csv => csv_line(
+                fields
+                    .iter()
+                    .map(|field| row.get(field).map(csv_value).unwrap_or_default()),
+            ),
+            ExportFormat::Ndjson => {
+                let mut line = serde_json::to_vec(&row).unwrap_or_default();
+                line.push(b'\n');
+                Bytes::from(line)
+            }
+        })
+    });
+
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"records.{}\"", format.extension()),
+        ))
+        .streaming(stream::iter(header.map(Ok)).chain(rows))
+}
+
+#[derive(Deserialize)]
+pub struct ExportParquetQuery {
+    #[serde(default, deserialize_with = "crate::serde_utils::deserialize_opt_date")]
+    from: Option<NaiveDate>,
+    #[serde(default, deserialize_with = "crate::serde_utils::deserialize_opt_date")]
+    to: Option<NaiveDate>,
+}
+
+/// Writes a Parquet partition per service day in the range to `files.parquet_dir`.
+pub async fn export_parquet(
+    query: web::Query<ExportParquetQuery>,
+    data: web::Data<SharedAppState>,
+) -> impl Responder {
+    #[derive(Serialize)]
+    struct Response {
+        count: usize,
+        days: Vec<ExportedDay>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    }
+    let (pg_pool, config) = {
+        let state = data.read().await;
+        (state.pg_pool.clone(), state.config.clone())
+    };
+    let to = query
+        .to
+        .unwrap_or_else(|| config.septa.service_date(Utc::now()));
+    let from = query.from.unwrap_or(to);
+    if from > to || (to - from).num_days() >= MAX_PARQUET_EXPORT_DAYS {
+        return (
+            Json(Response {
+                count: 0,
+                days: Vec::new(),
+                error: Some(format!(
+                    "from must not be after to, and the range must be at most {} days",
+                    MAX_PARQUET_EXPORT_DAYS
+                )),
+            }),
+            StatusCode::BAD_REQUEST,
+        );
+    }
+    let Ok(_exporting) = PARQUET_EXPORT.try_lock() else {
+        return (
+            Json(Response {
+                count: 0,
+                days: Vec::new(),
+                error: Some("A Parquet export is already running".to_string()),
+            }),
+            StatusCode::CONFLICT,
+        );
+    };
+
+    let dir = Path::new(&config.files.parquet_dir);
+    match parquet::export_range(&pg_pool, &config.septa, from, to, dir).await {
+        Ok(days) => (
+            Json(Response {
+                count: days.len(),
+                days,
+                error: None,
+            }),
+            StatusCode::OK,
+        ),
+        Err(e) => {
+            let err_str = format!("Error exporting parquet: {e:?}");
+            error!("{}", err_str);
+            (
+                Json(Response {
+                    count: 0,
+                    days: Vec::new(),
+                    error: Some(err_str),
+                }),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use serde_json::json;
+
+    fn negotiate(format: Option<ExportFormat>, accept: Option<&str>) -> ExportFormat {
+        let mut req = TestRequest::default();
+        if let Some(accept) = accept {
+            req = req.insert_header((header::ACCEPT, accept));
+        }
+        ExportFormat::negotiate(format, &req.to_http_request())
+    }
+
+    #[test]
+    fn format_negotiation() {
+        assert_eq!(negotiate(None, None), ExportFormat::Ndjson);
+        assert_eq!(
+            negotiate(None, Some("application/json")),
+            ExportFormat::Ndjson
+        );
+        assert_eq!(
+            negotiate(None, Some("text/html, text/csv;q=0.9")),
+            ExportFormat::Csv
+        );
+        // The format parameter wins over the header.
+        assert_eq!(
+            negotiate(Some(ExportFormat::Ndjson), Some("text/csv")),
+            ExportFormat::Ndjson
+        );
+        assert_eq!(negotiate(Some(ExportFormat::Csv), None), ExportFormat::Csv);
+    }
+
+    #[test]
+    fn csv_quoting() {
+        let values = [
+            json!("1001"),
+            json!("Paoli, PA"),
+            json!("the \"local\""),
+            json!(null),
+            json!(3),
+            json!(true),
+        ];
+        assert_eq!(
+            csv_line(values.iter().map(csv_value)),
+            Bytes::from_static(b"1001,\"Paoli, PA\",\"the \"\"local\"\"\",,3,true\n")
+        );
+        assert_eq!(
+            csv_line(["a", "b"].map(String::from)),
+            Bytes::from_static(b"a,b\n")
+        );
+    }
+}
@@ -1,34 +1,125 @@
 use actix_web::{
     HttpRequest, HttpResponse, Responder, ResponseError,
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
     error::QueryPayloadError,
     http::StatusCode,
+    middleware::Next,
     web::{self, Json, QueryConfig},
 };
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDate, Utc};
 use futures::StreamExt;
 use serde::{Deserialize, Serialize};
 use std::{fmt::Display, sync::Arc};
 
 use crate::{
     SharedAppState,
-    db::{QueryOrdering, tracking::Changed},
+    analytics::{
+        self,
+        diff::{self, NetworkDiff},
+        fleet::{self, DailyFleet},
+        otp::{self, OtpFilter, OtpReport},
+        schedule::{self, ScheduleComparison},
+        segments::{self, SegmentReport},
+    },
+    db::{
+        QueryOrdering,
+        cursor::{Cursor, Pagination},
+        tracking::{Changed, Fetch, HourlyFetches},
+    },
+    metrics::METRICS,
     septa::{
-        query_builder::QueryBuilder,
-        train_view::{TrainView, enforce_limit_bounds},
+        backoff::CircuitState, consist::CarAppearance, query_builder::QueryBuilder,
+        stations::Station, train_run::TrainRun, train_view::TrainView,
     },
 };
 
+mod export;
+mod realtime;
+mod socket;
+mod stream;
+
 pub fn routes(cfg: &mut web::ServiceConfig) {
     cfg.app_data(QueryConfig::default().error_handler(query_error_handler))
+        .route("/metrics", web::get().to(metrics))
+        .service(
+            web::scope("/gtfs-rt")
+                .route("/trip-updates.pb", web::get().to(realtime::trip_updates))
+                .route(
+                    "/trip-updates.json",
+                    web::get().to(realtime::trip_updates_json),
+                )
+                .route(
+                    "/vehicle-positions.pb",
+                    web::get().to(realtime::vehicle_positions),
+                )
+                .route(
+                    "/vehicle-positions.json",
+                    web::get().to(realtime::vehicle_positions_json),
+                ),
+        )
         .service(
             web::scope("/api")
+                .route("/status", web::get().to(ingest_status))
                 .route("/current", web::get().to(current_trains))
+                .route("/snapshot", web::get().to(snapshot))
+                .route("/diff", web::get().to(snapshot_diff))
                 .route("/train/{id}", web::get().to(get_train))
+                .route("/train/{id}/changes", web::get().to(get_train_changes))
+                .route("/train/{id}/runs", web::get().to(get_train_runs))
+                .route("/train/{id}/runs/{date}", web::get().to(get_train_run))
+                .route(
+                    "/train/{id}/runs/{date}/schedule",
+                    web::get().to(get_train_run_schedule),
+                )
                 .route("/recent_changes", web::get().to(most_recent_changes))
-                .route("/query", web::post().to(query_train)),
+                .route("/stats/otp", web::get().to(otp_stats))
+                .route("/stats/segments", web::get().to(segment_stats))
+                .route("/fleet/daily", web::get().to(fleet_daily))
+                .route("/car/{car_number}", web::get().to(get_car))
+                .route("/stations", web::get().to(get_stations))
+                .route("/stations/{id}", web::get().to(get_station))
+                .route("/stream/changes", web::get().to(stream::stream_changes))
+                .route("/ws/trains", web::get().to(socket::train_updates))
+                .route("/query", web::post().to(query_train))
+                .route("/export/records", web::post().to(export::export_records))
+                .route("/export/parquet", web::post().to(export::export_parquet)),
         );
 }
 
+/// Counts and times every request by its route pattern, so `/api/train/{id}` is one series
+/// rather than one per train.
+pub async fn record_metrics(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> actix_web::Result<ServiceResponse<impl MessageBody>> {
+    let method = req.method().to_string();
+    let route = req
+        .match_pattern()
+        .unwrap_or_else(|| "unmatched".to_string());
+    let timer = METRICS
+        .http_request_duration
+        .with_label_values(&[&method, &route])
+        .start_timer();
+    let res = next.call(req).await;
+    timer.observe_duration();
+    let status = match &res {
+        Ok(res) => res.status(),
+        Err(err) => err.as_response_error().status_code(),
+    };
+    METRICS
+        .http_requests
+        .with_label_values(&[&method, &route, status.as_str()])
+        .inc();
+    res
+}
+
+async fn metrics() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(METRICS.render())
+}
+
 #[derive(Debug, Serialize)]
 struct QeResponse {
     source: String,
@@ -70,34 +161,24 @@ async fn current_trains(
     query: web::Query<GetCurrentQuery>,
     data: web::Data<SharedAppState>,
 ) -> impl Responder {
-    let two_am_today = chrono::Local::now()
-        .with_time(chrono::NaiveTime::from_hms_opt(2, 0, 0).unwrap())
-        .unwrap()
-        .to_utc();
-    let count = enforce_limit_bounds(query.limit);
+    let state = data.read().await;
+    let service_day_start = state.config.septa.service_day_start(chrono::Local::now());
+    let count = state.config.api.enforce_limit_bounds(query.limit);
     let all = query.all.unwrap_or(false);
     let line = query.line.as_ref();
-    let recent = data
-        .read()
-        .await
+    let recent = state
         .train_statuses
         .iter()
         .filter_map(|tv| {
             if let Some(ref mri) = tv.1.most_recent_item {
-                if let Some(line) = line {
-                    if *line != mri.line {
-                        return None;
-                    }
+                if line.is_some_and(|line| *line != mri.line) {
+                    return None;
                 }
 
-                if all {
+                if all || mri.timestamp > service_day_start {
                     Some(mri.clone())
                 } else {
-                    if mri.timestamp > two_am_today {
-                        Some(mri.clone())
-                    } else {
-                        None
-                    }
+                    None
                 }
             } else {
                 None
@@ -119,6 +200,226 @@ async fn current_trains(
     )
 }
 
+#[derive(Deserialize, Debug)]
+pub struct SnapshotQuery {
+    at: i64,
+    all: Option<bool>,
+    line: Option<String>,
+    limit: Option<i64>,
+}
+/// `/api/current` as it would have been at `at`, rebuilt from `records`.
+async fn snapshot(
+    query: web::Query<SnapshotQuery>,
+    data: web::Data<SharedAppState>,
+) -> impl Responder {
+    #[derive(Serialize)]
+    struct Response {
+        count: u32,
+        statuses: Vec<TrainView>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    }
+    let Some(at) = DateTime::from_timestamp(query.at, 0) else {
+        return (
+            Json(Response {
+                count: 0,
+                statuses: vec![],
+                error: Some(format!("Invalid timestamp: {}", query.at)),
+            }),
+            StatusCode::BAD_REQUEST,
+        );
+    };
+    let (pg_pool, since, count) = {
+        let state = data.read().await;
+        let septa = &state.config.septa;
+        let since = (!query.all.unwrap_or(false))
+            .then(|| septa.service_date_bounds(septa.service_date(at)).0);
+        (
+            state.pg_pool.clone(),
+            since,
+            state.config.api.enforce_limit_bounds(query.limit),
+        )
+    };
+
+    match TrainView::get_snapshot(pg_pool, Some(at), since).await {
+        Ok(statuses) => {
+            let statuses: Vec<TrainView> = statuses
+                .into_iter()
+                .filter(|tv| query.line.as_ref().is_none_or(|line| *line == tv.line))
+                .take(count as usize)
+                .collect();
+            (
+                Json(Response {
+                    count: statuses.len() as u32,
+                    statuses,
+                    error: None,
+                }),
+                StatusCode::OK,
+            )
+        }
+        Err(e) => {
+            let err_str = format!("Error fetching snapshot: {e:?}");
+            error!("{}", err_str);
+            (
+                Json(Response {
+                    count: 0,
+                    statuses: vec![],
+                    error: Some(err_str),
+                }),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SnapshotDiffQuery {
+    from: i64,
+    to: i64,
+    all: Option<bool>,
+    line: Option<String>,
+}
+/// Trains added, removed and changed between the snapshots at `from` and `to`.
+async fn snapshot_diff(
+    query: web::Query<SnapshotDiffQuery>,
+    data: web::Data<SharedAppState>,
+) -> impl Responder {
+    #[derive(Serialize)]
+    struct Response {
+        diff: Option<NetworkDiff>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    }
+    let (Some(from), Some(to)) = (
+        DateTime::from_timestamp(query.from, 0),
+        DateTime::from_timestamp(query.to, 0),
+    ) else {
+        return (
+            Json(Response {
+                diff: None,
+                error: Some(format!("Invalid timestamps: {}, {}", query.from, query.to)),
+            }),
+            StatusCode::BAD_REQUEST,
+        );
+    };
+    if from > to {
+        return (
+            Json(Response {
+                diff: None,
+                error: Some("from must not be after to".to_string()),
+            }),
+            StatusCode::BAD_REQUEST,
+        );
+    }
+    let (pg_pool, config) = {
+        let state = data.read().await;
+        (state.pg_pool.clone(), state.config.clone())
+    };
+
+    match diff::diff_snapshots(pg_pool, &config.septa, from, to, query.all.unwrap_or(false)).await {
+        Ok(mut diff) => {
+            if let Some(line) = &query.line {
+                diff.lines.retain(|name, _| name == line);
+            }
+            (
+                Json(Response {
+                    diff: Some(diff),
+                    error: None,
+                }),
+                StatusCode::OK,
+            )
+        }
+        Err(e) => {
+            let err_str = format!("Error diffing snapshots: {e:?}");
+            error!("{}", err_str);
+            (
+                Json(Response {
+                    diff: None,
+                    error: Some(err_str),
+                }),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    }
+}
+
+/// Hours of fetch history `/api/status` reports by default, and at most.
+const DEFAULT_STATUS_HOURS: i64 = 24;
+const MAX_STATUS_HOURS: i64 = 24 * 7;
+
+#[derive(Deserialize, Debug)]
+pub struct IngestStatusQuery {
+    hours: Option<i64>,
+}
+async fn ingest_status(
+    query: web::Query<IngestStatusQuery>,
+    data: web::Data<SharedAppState>,
+) -> impl Responder {
+    #[derive(Serialize, Default)]
+    struct Response {
+        /// Latest fetch that got a TrainView, whether or not anything had changed.
+        #[serde(serialize_with = "crate::serde_utils::serialize_opt_date_time")]
+        last_success_at: Option<DateTime<Utc>>,
+        /// Latest fetch that had changed trains to store.
+        #[serde(serialize_with = "crate::serde_utils::serialize_opt_date_time")]
+        last_update_at: Option<DateTime<Utc>>,
+        /// The breaker and its failure run live in this process, so they start over when the
+        /// server restarts, unlike the fetch history below.
+        circuit_state: CircuitState,
+        consecutive_failures: u32,
+        #[serde(serialize_with = "crate::serde_utils::serialize_opt_date_time")]
+        last_failure_at: Option<DateTime<Utc>>,
+        last_error: Option<String>,
+        tracked_trains: usize,
+        hourly: Vec<HourlyFetches>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    }
+    let (pg_pool, response) = {
+        let state = data.read().await;
+        (
+            state.pg_pool.clone(),
+            Response {
+                circuit_state: state.circuit_breaker.state,
+                consecutive_failures: state.circuit_breaker.consecutive_failures,
+                last_failure_at: state.circuit_breaker.last_failure_at,
+                last_error: state.circuit_breaker.last_error.clone(),
+                tracked_trains: state.train_statuses.len(),
+                ..Default::default()
+            },
+        )
+    };
+    let hours = query
+        .hours
+        .unwrap_or(DEFAULT_STATUS_HOURS)
+        .clamp(1, MAX_STATUS_HOURS);
+    let since = Utc::now() - chrono::Duration::hours(hours - 1);
+
+    let result = async {
+        Ok::<_, anyhow::Error>(Response {
+            last_success_at: Fetch::last_with_status(&pg_pool, &["OK", "UNCHANGED"]).await?,
+            last_update_at: Fetch::last_with_status(&pg_pool, &["OK"]).await?,
+            hourly: HourlyFetches::since(&pg_pool, since).await?,
+            ..response
+        })
+    }
+    .await;
+    match result {
+        Ok(response) => (Json(response), StatusCode::OK),
+        Err(e) => {
+            let err_str = format!("Error reading fetches: {e:?}");
+            error!("{}", err_str);
+            (
+                Json(Response {
+                    error: Some(err_str),
+                    ..Default::default()
+                }),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    }
+}
+
 async fn most_recent_changes(data: web::Data<SharedAppState>) -> impl Responder {
     #[derive(Serialize)]
     struct Change {
@@ -142,9 +443,9 @@ async fn most_recent_changes(data: web::Data<SharedAppState>) -> impl Responder
                 let relevant_changes: Vec<Changed> = changes
                     .iter()
                     .filter(|c| c.changed_at >= until)
-                    .map(|c| c.clone())
+                    .cloned()
                     .collect();
-                if relevant_changes.len() > 0 {
+                if !relevant_changes.is_empty() {
                     Some(Change {
                         trainno: tv.0.clone(),
                         changes: relevant_changes.clone(),
@@ -166,36 +467,69 @@ struct GetTrainPath {
 #[derive(Deserialize)]
 struct GetTrainQuery {
     limit: Option<i64>,
-    before: Option<i64>,
-    after: Option<i64>,
+    #[serde(
+        default,
+        deserialize_with = "crate::serde_utils::deserialize_opt_timestamp"
+    )]
+    before: Option<DateTime<Utc>>,
+    #[serde(
+        default,
+        deserialize_with = "crate::serde_utils::deserialize_opt_timestamp"
+    )]
+    after: Option<DateTime<Utc>>,
+    order: Option<QueryOrdering>,
+}
+#[derive(Deserialize)]
+struct GetTrainRecordsQuery {
+    limit: Option<i64>,
+    #[serde(
+        default,
+        deserialize_with = "crate::serde_utils::deserialize_opt_timestamp"
+    )]
+    before: Option<DateTime<Utc>>,
+    #[serde(
+        default,
+        deserialize_with = "crate::serde_utils::deserialize_opt_timestamp"
+    )]
+    after: Option<DateTime<Utc>>,
     order: Option<QueryOrdering>,
+    cursor: Option<Cursor>,
 }
 async fn get_train(
     path: web::Path<GetTrainPath>,
-    query: web::Query<GetTrainQuery>,
+    query: web::Query<GetTrainRecordsQuery>,
     data: web::Data<SharedAppState>,
 ) -> impl Responder {
     #[derive(Serialize)]
     struct Response {
         count: usize,
         records: Vec<TrainView>,
+        next_cursor: Option<Cursor>,
+        prev_cursor: Option<Cursor>,
     }
-    let pg_pool = data.read().await.pg_pool.clone();
+    let (pg_pool, limit) = {
+        let state = data.read().await;
+        (
+            state.pg_pool.clone(),
+            state.config.api.enforce_limit_bounds(query.limit),
+        )
+    };
 
     match TrainView::fetch_for_train(
         pg_pool,
         &path.id,
-        query.limit,
-        query.before.and_then(|ts| DateTime::from_timestamp(ts, 0)),
-        query.after.and_then(|ts| DateTime::from_timestamp(ts, 0)),
-        query.order,
+        Pagination::new(limit, query.order, query.cursor),
+        query.before,
+        query.after,
     )
     .await
     {
-        Ok(records) => (
+        Ok(page) => (
             Json(Response {
-                count: records.len(),
-                records,
+                count: page.items.len(),
+                records: page.items,
+                next_cursor: page.next_cursor,
+                prev_cursor: page.prev_cursor,
             }),
             StatusCode::OK,
         ),
@@ -205,6 +539,494 @@ async fn get_train(
                 Json(Response {
                     count: 0,
                     records: Vec::new(),
+                    next_cursor: None,
+                    prev_cursor: None,
+                }),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    }
+}
+
+async fn get_train_changes(
+    path: web::Path<GetTrainPath>,
+    query: web::Query<GetTrainRecordsQuery>,
+    data: web::Data<SharedAppState>,
+) -> impl Responder {
+    #[derive(Serialize)]
+    struct Response {
+        count: usize,
+        changes: Vec<Changed>,
+        next_cursor: Option<Cursor>,
+        prev_cursor: Option<Cursor>,
+    }
+    let (pg_pool, limit) = {
+        let state = data.read().await;
+        (
+            state.pg_pool.clone(),
+            state.config.api.enforce_limit_bounds(query.limit),
+        )
+    };
+
+    match Changed::fetch_for_train(
+        pg_pool,
+        &path.id,
+        Pagination::new(limit, query.order, query.cursor),
+        query.before,
+        query.after,
+    )
+    .await
+    {
+        Ok(page) => (
+            Json(Response {
+                count: page.items.len(),
+                changes: page.items,
+                next_cursor: page.next_cursor,
+                prev_cursor: page.prev_cursor,
+            }),
+            StatusCode::OK,
+        ),
+        Err(e) => {
+            error!("Error fetching: {e}");
+            (
+                Json(Response {
+                    count: 0,
+                    changes: Vec::new(),
+                    next_cursor: None,
+                    prev_cursor: None,
+                }),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    }
+}
+
+/// A train's runs, one per service day. `before` and `after` select whole service days, so the
+/// runs can include records on either side of them.
+async fn get_train_runs(
+    path: web::Path<GetTrainPath>,
+    query: web::Query<GetTrainQuery>,
+    data: web::Data<SharedAppState>,
+) -> impl Responder {
+    #[derive(Serialize)]
+    struct Response {
+        count: usize,
+        runs: Vec<TrainRun>,
+    }
+    let (pg_pool, config, limit) = {
+        let state = data.read().await;
+        (
+            state.pg_pool.clone(),
+            state.config.clone(),
+            state.config.api.enforce_limit_bounds(query.limit),
+        )
+    };
+
+    match TrainRun::fetch_for_train(
+        pg_pool,
+        &config.septa,
+        &path.id,
+        limit,
+        query.before,
+        query.after,
+        query.order,
+    )
+    .await
+    {
+        Ok(runs) => (
+            Json(Response {
+                count: runs.len(),
+                runs,
+            }),
+            StatusCode::OK,
+        ),
+        Err(e) => {
+            error!("Error fetching: {e}");
+            (
+                Json(Response {
+                    count: 0,
+                    runs: Vec::new(),
+                }),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct GetTrainRunPath {
+    id: String,
+    date: String,
+}
+async fn get_train_run(
+    path: web::Path<GetTrainRunPath>,
+    data: web::Data<SharedAppState>,
+) -> impl Responder {
+    #[derive(Serialize)]
+    struct Response {
+        run: Option<TrainRun>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    }
+    let service_date = match NaiveDate::parse_from_str(&path.date, "%Y-%m-%d") {
+        Ok(date) => date,
+        Err(err) => {
+            return (
+                Json(Response {
+                    run: None,
+                    error: Some(format!("Invalid date, expected YYYY-MM-DD: {err}")),
+                }),
+                StatusCode::BAD_REQUEST,
+            );
+        }
+    };
+    let (pg_pool, config) = {
+        let state = data.read().await;
+        (state.pg_pool.clone(), state.config.clone())
+    };
+
+    match TrainRun::fetch_for_date(pg_pool, &config.septa, &path.id, service_date).await {
+        Ok(Some(run)) => (
+            Json(Response {
+                run: Some(run),
+                error: None,
+            }),
+            StatusCode::OK,
+        ),
+        Ok(None) => (
+            Json(Response {
+                run: None,
+                error: None,
+            }),
+            StatusCode::NOT_FOUND,
+        ),
+        Err(e) => {
+            let err_str = format!("Error fetching: {e:?}");
+            error!("{}", err_str);
+            (
+                Json(Response {
+                    run: None,
+                    error: Some(err_str),
+                }),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    }
+}
+
+async fn get_train_run_schedule(
+    path: web::Path<GetTrainRunPath>,
+    data: web::Data<SharedAppState>,
+) -> impl Responder {
+    #[derive(Serialize)]
+    struct Response {
+        comparison: Option<ScheduleComparison>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    }
+    let service_date = match NaiveDate::parse_from_str(&path.date, "%Y-%m-%d") {
+        Ok(date) => date,
+        Err(err) => {
+            return (
+                Json(Response {
+                    comparison: None,
+                    error: Some(format!("Invalid date, expected YYYY-MM-DD: {err}")),
+                }),
+                StatusCode::BAD_REQUEST,
+            );
+        }
+    };
+    let (pg_pool, config) = {
+        let state = data.read().await;
+        (state.pg_pool.clone(), state.config.clone())
+    };
+
+    match schedule::compare_run(pg_pool, &config.septa, &path.id, service_date).await {
+        Ok(Some(comparison)) => (
+            Json(Response {
+                comparison: Some(comparison),
+                error: None,
+            }),
+            StatusCode::OK,
+        ),
+        Ok(None) => (
+            Json(Response {
+                comparison: None,
+                error: None,
+            }),
+            StatusCode::NOT_FOUND,
+        ),
+        Err(e) => {
+            let err_str = format!("Error comparing to schedule: {e:?}");
+            error!("{}", err_str);
+            (
+                Json(Response {
+                    comparison: None,
+                    error: Some(err_str),
+                }),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct OtpQuery {
+    #[serde(default, deserialize_with = "crate::serde_utils::deserialize_opt_date")]
+    from: Option<NaiveDate>,
+    #[serde(default, deserialize_with = "crate::serde_utils::deserialize_opt_date")]
+    to: Option<NaiveDate>,
+    line: Option<String>,
+    trainno: Option<String>,
+    service: Option<String>,
+    #[serde(
+        default,
+        deserialize_with = "crate::serde_utils::deserialize_opt_i32_list"
+    )]
+    thresholds: Option<Vec<i32>>,
+}
+async fn otp_stats(query: web::Query<OtpQuery>, data: web::Data<SharedAppState>) -> impl Responder {
+    #[derive(Serialize)]
+    struct Response {
+        #[serde(flatten)]
+        report: Option<OtpReport>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    }
+    let (pg_pool, config) = {
+        let state = data.read().await;
+        (state.pg_pool.clone(), state.config.clone())
+    };
+    let query = query.into_inner();
+    // Runs on the current service day are still going, so it's only included when asked for.
+    let to = query
+        .to
+        .unwrap_or_else(|| config.septa.service_date(Utc::now()) - chrono::Duration::days(1));
+    let from = query.from.unwrap_or_else(|| to - chrono::Duration::days(6));
+    if from > to || (to - from).num_days() >= analytics::MAX_RANGE_DAYS {
+        return (
+            Json(Response {
+                report: None,
+                error: Some(format!(
+                    "from must not be after to, and the range must be under {} days",
+                    analytics::MAX_RANGE_DAYS
+                )),
+            }),
+            StatusCode::BAD_REQUEST,
+        );
+    }
+    let filter = OtpFilter {
+        line: query.line,
+        trainno: query.trainno,
+        service: query.service,
+    };
+    let thresholds = query.thresholds.unwrap_or(otp::DEFAULT_THRESHOLDS.to_vec());
+
+    match otp::compute(pg_pool, &config.septa, from, to, &filter, thresholds).await {
+        Ok(report) => (
+            Json(Response {
+                report: Some(report),
+                error: None,
+            }),
+            StatusCode::OK,
+        ),
+        Err(e) => {
+            let err_str = format!("Error computing stats: {e:?}");
+            error!("{}", err_str);
+            (
+                Json(Response {
+                    report: None,
+                    error: Some(err_str),
+                }),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SegmentQuery {
+    #[serde(default, deserialize_with = "crate::serde_utils::deserialize_opt_date")]
+    from: Option<NaiveDate>,
+    #[serde(default, deserialize_with = "crate::serde_utils::deserialize_opt_date")]
+    to: Option<NaiveDate>,
+    line: Option<String>,
+    trainno: Option<String>,
+}
+async fn segment_stats(
+    query: web::Query<SegmentQuery>,
+    data: web::Data<SharedAppState>,
+) -> impl Responder {
+    #[derive(Serialize)]
+    struct Response {
+        #[serde(flatten)]
+        report: Option<SegmentReport>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    }
+    let (pg_pool, config) = {
+        let state = data.read().await;
+        (state.pg_pool.clone(), state.config.clone())
+    };
+    let to = query
+        .to
+        .unwrap_or_else(|| config.septa.service_date(Utc::now()));
+    let from = query.from.unwrap_or_else(|| to - chrono::Duration::days(6));
+    if from > to || (to - from).num_days() >= analytics::MAX_RANGE_DAYS {
+        return (
+            Json(Response {
+                report: None,
+                error: Some(format!(
+                    "from must not be after to, and the range must be under {} days",
+                    analytics::MAX_RANGE_DAYS
+                )),
+            }),
+            StatusCode::BAD_REQUEST,
+        );
+    }
+
+    match segments::compute(
+        pg_pool,
+        &config.septa,
+        from,
+        to,
+        query.line.as_deref(),
+        query.trainno.as_deref(),
+    )
+    .await
+    {
+        Ok(report) => (
+            Json(Response {
+                report: Some(report),
+                error: None,
+            }),
+            StatusCode::OK,
+        ),
+        Err(e) => {
+            let err_str = format!("Error computing segment times: {e:?}");
+            error!("{}", err_str);
+            (
+                Json(Response {
+                    report: None,
+                    error: Some(err_str),
+                }),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct FleetDailyQuery {
+    #[serde(default, deserialize_with = "crate::serde_utils::deserialize_opt_date")]
+    from: Option<NaiveDate>,
+    #[serde(default, deserialize_with = "crate::serde_utils::deserialize_opt_date")]
+    to: Option<NaiveDate>,
+}
+async fn fleet_daily(
+    query: web::Query<FleetDailyQuery>,
+    data: web::Data<SharedAppState>,
+) -> impl Responder {
+    #[derive(Serialize)]
+    struct Response {
+        count: usize,
+        days: Vec<DailyFleet>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    }
+    let (pg_pool, config) = {
+        let state = data.read().await;
+        (state.pg_pool.clone(), state.config.clone())
+    };
+    let to = query
+        .to
+        .unwrap_or_else(|| config.septa.service_date(Utc::now()));
+    let from = query.from.unwrap_or(to);
+    if from > to || (to - from).num_days() >= analytics::MAX_RANGE_DAYS {
+        return (
+            Json(Response {
+                count: 0,
+                days: Vec::new(),
+                error: Some(format!(
+                    "from must not be after to, and the range must be under {} days",
+                    analytics::MAX_RANGE_DAYS
+                )),
+            }),
+            StatusCode::BAD_REQUEST,
+        );
+    }
+
+    match fleet::daily_usage(pg_pool, &config.septa, &config.fleet, from, to).await {
+        Ok(days) => (
+            Json(Response {
+                count: days.len(),
+                days,
+                error: None,
+            }),
+            StatusCode::OK,
+        ),
+        Err(e) => {
+            let err_str = format!("Error computing fleet usage: {e:?}");
+            error!("{}", err_str);
+            (
+                Json(Response {
+                    count: 0,
+                    days: Vec::new(),
+                    error: Some(err_str),
+                }),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct GetCarPath {
+    car_number: i32,
+}
+async fn get_car(
+    path: web::Path<GetCarPath>,
+    query: web::Query<GetTrainQuery>,
+    data: web::Data<SharedAppState>,
+) -> impl Responder {
+    #[derive(Serialize)]
+    struct Response {
+        count: usize,
+        appearances: Vec<CarAppearance>,
+    }
+    let (pg_pool, config, limit) = {
+        let state = data.read().await;
+        (
+            state.pg_pool.clone(),
+            state.config.clone(),
+            state.config.api.enforce_limit_bounds(query.limit),
+        )
+    };
+
+    match CarAppearance::fetch_for_car(
+        pg_pool,
+        &config.septa,
+        path.car_number,
+        limit,
+        query.before,
+        query.after,
+        query.order,
+    )
+    .await
+    {
+        Ok(appearances) => (
+            Json(Response {
+                count: appearances.len(),
+                appearances,
+            }),
+            StatusCode::OK,
+        ),
+        Err(e) => {
+            error!("Error fetching: {e}");
+            (
+                Json(Response {
+                    count: 0,
+                    appearances: Vec::new(),
                 }),
                 StatusCode::INTERNAL_SERVER_ERROR,
             )
@@ -215,19 +1037,45 @@ async fn get_train(
 #[derive(Deserialize)]
 struct QueryTrainQuery {
     limit: Option<i64>,
-    before: Option<i64>,
-    after: Option<i64>,
+    #[serde(
+        default,
+        deserialize_with = "crate::serde_utils::deserialize_opt_timestamp"
+    )]
+    before: Option<DateTime<Utc>>,
+    #[serde(
+        default,
+        deserialize_with = "crate::serde_utils::deserialize_opt_timestamp"
+    )]
+    after: Option<DateTime<Utc>>,
     order: Option<QueryOrdering>,
+    cursor: Option<Cursor>,
 }
 async fn query_train(
     mut payload: web::Payload,
     query: web::Query<QueryTrainQuery>,
     data: web::Data<SharedAppState>,
 ) -> impl Responder {
+    /// Whole records, or only the fields the body selected.
+    #[derive(Serialize)]
+    #[serde(untagged)]
+    enum Records {
+        Full(Vec<TrainView>),
+        Fields(Vec<serde_json::Map<String, serde_json::Value>>),
+    }
+    impl Records {
+        fn len(&self) -> usize {
+            match self {
+                Records::Full(records) => records.len(),
+                Records::Fields(records) => records.len(),
+            }
+        }
+    }
     #[derive(Serialize)]
     struct Response {
         count: usize,
-        records: Vec<TrainView>,
+        records: Records,
+        next_cursor: Option<Cursor>,
+        prev_cursor: Option<Cursor>,
         #[serde(skip_serializing_if = "Option::is_none")]
         error: Option<String>,
     }
@@ -239,7 +1087,9 @@ async fn query_train(
             return (
                 Json(Response {
                     count: 0,
-                    records: Vec::new(),
+                    records: Records::Full(Vec::new()),
+                    next_cursor: None,
+                    prev_cursor: None,
                     error: Some(err_str),
                 }),
                 StatusCode::INTERNAL_SERVER_ERROR,
@@ -255,28 +1105,66 @@ async fn query_train(
         return (
             Json(Response {
                 count: 0,
-                records: Vec::new(),
+                records: Records::Full(Vec::new()),
+                next_cursor: None,
+                prev_cursor: None,
+                error: Some(err_str),
+            }),
+            StatusCode::BAD_REQUEST,
+        );
+    }
+    let body = body.unwrap();
+    if let Err(err_str) = body.validate() {
+        return (
+            Json(Response {
+                count: 0,
+                records: Records::Full(Vec::new()),
+                next_cursor: None,
+                prev_cursor: None,
                 error: Some(err_str),
             }),
             StatusCode::BAD_REQUEST,
         );
     }
 
-    let pg_pool = data.read().await.pg_pool.clone();
-    match TrainView::query_trains(
-        pg_pool,
-        body.unwrap(),
-        query.limit,
-        query.before.and_then(|ts| DateTime::from_timestamp(ts, 0)),
-        query.after.and_then(|ts| DateTime::from_timestamp(ts, 0)),
-        query.order,
-    )
-    .await
-    {
-        Ok(records) => (
+    let (pg_pool, limit) = {
+        let state = data.read().await;
+        (
+            state.pg_pool.clone(),
+            state.config.api.enforce_limit_bounds(query.limit),
+        )
+    };
+    let before = query.before;
+    let after = query.after;
+    let pagination = Pagination::new(limit, query.order, query.cursor);
+    let page = if body.fields.is_some() {
+        TrainView::query_fields(pg_pool, body, pagination, before, after)
+            .await
+            .map(|page| {
+                (
+                    Records::Fields(page.items),
+                    page.next_cursor,
+                    page.prev_cursor,
+                )
+            })
+    } else {
+        TrainView::query_trains(pg_pool, body, pagination, before, after)
+            .await
+            .map(|page| {
+                (
+                    Records::Full(page.items),
+                    page.next_cursor,
+                    page.prev_cursor,
+                )
+            })
+    };
+    match page {
+        Ok((records, next_cursor, prev_cursor)) => (
             Json(Response {
                 count: records.len(),
                 records,
+                next_cursor,
+                prev_cursor,
                 error: None,
             }),
             StatusCode::OK,
@@ -287,7 +1175,9 @@ async fn query_train(
             (
                 Json(Response {
                     count: 0,
-                    records: Vec::new(),
+                    records: Records::Full(Vec::new()),
+                    next_cursor: None,
+                    prev_cursor: None,
                     error: Some(err_str),
                 }),
                 StatusCode::OK,
@@ -295,3 +1185,103 @@ async fn query_train(
         }
     }
 }
+
+#[derive(Deserialize, Debug)]
+pub struct GetStationsQuery {
+    line: Option<String>,
+}
+async fn get_stations(
+    query: web::Query<GetStationsQuery>,
+    data: web::Data<SharedAppState>,
+) -> impl Responder {
+    #[derive(Serialize)]
+    struct Response {
+        count: usize,
+        stations: Vec<Station>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    }
+    let pg_pool = data.read().await.pg_pool.clone();
+
+    match Station::fetch_all(&pg_pool, query.line.as_deref()).await {
+        Ok(stations) => (
+            Json(Response {
+                count: stations.len(),
+                stations,
+                error: None,
+            }),
+            StatusCode::OK,
+        ),
+        Err(e) => {
+            let err_str = format!("Error fetching: {e:?}");
+            error!("{}", err_str);
+            (
+                Json(Response {
+                    count: 0,
+                    stations: Vec::new(),
+                    error: Some(err_str),
+                }),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    }
+}
+
+async fn get_station(path: web::Path<String>, data: web::Data<SharedAppState>) -> impl Responder {
+    #[derive(Serialize)]
+    struct Response {
+        station: Option<Station>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    }
+    let pg_pool = data.read().await.pg_pool.clone();
+
+    match Station::fetch(&pg_pool, &path).await {
+        Ok(Some(station)) => (
+            Json(Response {
+                station: Some(station),
+                error: None,
+            }),
+            StatusCode::OK,
+        ),
+        Ok(None) => (
+            Json(Response {
+                station: None,
+                error: None,
+            }),
+            StatusCode::NOT_FOUND,
+        ),
+        Err(e) => {
+            let err_str = format!("Error fetching: {e:?}");
+            error!("{}", err_str);
+            (
+                Json(Response {
+                    station: None,
+                    error: Some(err_str),
+                }),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn out_of_range_bounds_are_rejected() {
+        let query =
+            web::Query::<GetTrainRecordsQuery>::from_query("before=1792200000&after=0").unwrap();
+        assert_eq!(query.before.map(|at| at.timestamp()), Some(1_792_200_000));
+        assert_eq!(query.after.map(|at| at.timestamp()), Some(0));
+        assert!(web::Query::<GetTrainRecordsQuery>::from_query("").is_ok());
+
+        for bound in ["before", "after"] {
+            let query = format!("{bound}={}", i64::MAX);
+            assert!(web::Query::<GetTrainRecordsQuery>::from_query(&query).is_err());
+            assert!(web::Query::<GetTrainQuery>::from_query(&query).is_err());
+            assert!(web::Query::<QueryTrainQuery>::from_query(&query).is_err());
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use actix_web::{
+    HttpResponse, Responder,
+    http::StatusCode,
+    web::{self, Json},
+};
+use chrono::Utc;
+use prost::Message;
+use serde::Serialize;
+
+use crate::{
+    SharedAppState,
+    gtfs::{
+        realtime::{self, FeedMessage},
+        schedule::Trip,
+    },
+};
+
+#[derive(Debug, Clone, Copy)]
+enum Feed {
+    TripUpdates,
+    VehiclePositions,
+}
+
+/// Builds a feed from the tracked view of every train seen this service day.
+async fn build_feed(data: &SharedAppState, feed: Feed) -> anyhow::Result<FeedMessage> {
+    let now = Utc::now();
+    let (pg_pool, service_date, mut trains) = {
+        let state = data.read().await;
+        let service_date = state.config.septa.service_date(now);
+        let (service_day_start, _) = state.config.septa.service_date_bounds(service_date);
+        let trains: Vec<_> = state
+            .train_statuses
+            .values()
+            .filter_map(|tracking| tracking.most_recent_item.clone())
+            .filter(|train| train.last_seen() >= service_day_start)
+            .collect();
+        (state.pg_pool.clone(), service_date, trains)
+    };
+    trains.sort_by(|a, b| a.trainno.cmp(&b.trainno));
+    let trainnos: Vec<String> = trains.iter().map(|train| train.trainno.clone()).collect();
+    let trips = Trip::for_trains(&pg_pool, service_date, &trainnos).await?;
+    if trips.is_empty() && !trains.is_empty() {
+        // Without trips, trip updates are empty and vehicle positions carry no trip.
+        warn!(
+            "None of {} trains seen today run a scheduled trip on {service_date}. Has `import-gtfs` been run?",
+            trains.len()
+        );
+    }
+    Ok(match feed {
+        Feed::TripUpdates => realtime::trip_updates(&trains, &trips, service_date, now),
+        Feed::VehiclePositions => realtime::vehicle_positions(&trains, &trips, service_date, now),
+    })
+}
+
+async fn protobuf(data: &SharedAppState, feed: Feed) -> HttpResponse {
+    match build_feed(data, feed).await {
+        Ok(message) => HttpResponse::Ok()
+            .content_type("application/x-protobuf")
+            .body(message.encode_to_vec()),
+        Err(e) => {
+            let err_str = format!("Error building {feed:?} feed: {e:?}");
+            error!("{}", err_str);
+            HttpResponse::InternalServerError().body(err_str)
+        }
+    }
+}
+
+/// The same feed as JSON, for eyeballing what the protobuf endpoints serve.
+async fn json(data: &SharedAppState, feed: Feed) -> impl Responder + use<> {
+    #[derive(Serialize)]
+    struct Response {
+        feed: Option<FeedMessage>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    }
+    match build_feed(data, feed).await {
+        Ok(message) => (
+            Json(Response {
+                feed: Some(message),
+                error: None,
+            }),
+            StatusCode::OK,
+        ),
+        Err(e) => {
+            let err_str = format!("Error building {feed:?} feed: {e:?}");
+            error!("{}", err_str);
+            (
+                Json(Response {
+                    feed: None,
+                    error: Some(err_str),
+                }),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    }
+}
+
+pub async fn trip_updates(data: web::Data<SharedAppState>) -> impl Responder {
+    protobuf(&data, Feed::TripUpdates).await
+}
+
+pub async fn trip_updates_json(data: web::Data<SharedAppState>) -> impl Responder {
+    json(&data, Feed::TripUpdates).await
+}
+
+pub async fn vehicle_positions(data: web::Data<SharedAppState>) -> impl Responder {
+    protobuf(&data, Feed::VehiclePositions).await
+}
+
+pub async fn vehicle_positions_json(data: web::Data<SharedAppState>) -> impl Responder {
+    json(&data, Feed::VehiclePositions).await
+}
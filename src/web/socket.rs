@@ -0,0 +1,307 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use actix_web::{HttpRequest, HttpResponse, web};
+use actix_ws::{AggregatedMessage, AggregatedMessageStream, Session};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{Receiver, error::RecvError};
+
+use crate::{
+    SharedAppState,
+    db::tracking::Changed,
+    septa::train_view::{TrainUpdate, TrainView},
+};
+
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+/// Connections that haven't answered a ping (or sent anything) for this long are closed.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(45);
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum ClientMessage {
+    Subscribe {
+        #[serde(default)]
+        trains: Vec<String>,
+        #[serde(default)]
+        lines: Vec<String>,
+    },
+    Unsubscribe {
+        #[serde(default)]
+        trains: Vec<String>,
+        #[serde(default)]
+        lines: Vec<String>,
+    },
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ServerMessage<'a> {
+    /// The full set of subscriptions after a subscribe/unsubscribe.
+    Subscriptions {
+        trains: &'a HashSet<String>,
+        lines: &'a HashSet<String>,
+    },
+    Update {
+        train: &'a TrainView,
+        changes: &'a [Changed],
+    },
+    Error {
+        error: String,
+    },
+}
+
+#[derive(Default, Clone)]
+struct Subscriptions {
+    trains: HashSet<String>,
+    lines: HashSet<String>,
+}
+
+impl Subscriptions {
+    fn matches(&self, train: &TrainView) -> bool {
+        self.trains.contains(&train.trainno) || self.lines.contains(&train.line)
+    }
+
+    fn subscribe(&mut self, trains: Vec<String>, lines: Vec<String>) {
+        self.trains.extend(trains);
+        self.lines.extend(lines);
+    }
+
+    fn unsubscribe(&mut self, trains: &[String], lines: &[String]) {
+        trains.iter().for_each(|train| {
+            self.trains.remove(train);
+        });
+        lines.iter().for_each(|line| {
+            self.lines.remove(line);
+        });
+    }
+}
+
+async fn send(session: &mut Session, message: &ServerMessage<'_>) -> Result<(), actix_ws::Closed> {
+    session
+        .text(serde_json::to_string(message).unwrap_or_default())
+        .await
+}
+
+/// Sends the current view of every train that matches `new` but not `old`, so subscribers
+/// don't have to wait for the next poll.
+async fn send_current(
+    state: &SharedAppState,
+    session: &mut Session,
+    new: &Subscriptions,
+    old: &Subscriptions,
+) -> Result<(), actix_ws::Closed> {
+    let current: Vec<(Arc<TrainView>, Vec<Changed>)> = {
+        let state = state.read().await;
+        let service_day_start = state.config.septa.service_day_start(chrono::Local::now());
+        state
+            .train_statuses
+            .values()
+            .filter_map(|tracking| {
+                let train = tracking.most_recent_item.as_ref()?;
+                (train.timestamp > service_day_start && new.matches(train) && !old.matches(train))
+                    .then(|| {
+                        (
+                            train.clone(),
+                            tracking.latest_changes.clone().unwrap_or_default(),
+                        )
+                    })
+            })
+            .collect()
+    };
+    for (train, changes) in current {
+        send(
+            session,
+            &ServerMessage::Update {
+                train: &train,
+                changes: &changes,
+            },
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+async fn handle_client_message(
+    state: &SharedAppState,
+    session: &mut Session,
+    subscriptions: &mut Subscriptions,
+    text: &str,
+) -> Result<(), actix_ws::Closed> {
+    let message = match serde_json::from_str::<ClientMessage>(text) {
+        Ok(message) => message,
+        Err(e) => {
+            return send(
+                session,
+                &ServerMessage::Error {
+                    error: e.to_string(),
+                },
+            )
+            .await;
+        }
+    };
+
+    match message {
+        ClientMessage::Subscribe { trains, lines } => {
+            let previous = subscriptions.clone();
+            subscriptions.subscribe(trains, lines);
+            send(
+                session,
+                &ServerMessage::Subscriptions {
+                    trains: &subscriptions.trains,
+                    lines: &subscriptions.lines,
+                },
+            )
+            .await?;
+            send_current(state, session, subscriptions, &previous).await
+        }
+        ClientMessage::Unsubscribe { trains, lines } => {
+            subscriptions.unsubscribe(&trains, &lines);
+            send(
+                session,
+                &ServerMessage::Subscriptions {
+                    trains: &subscriptions.trains,
+                    lines: &subscriptions.lines,
+                },
+            )
+            .await
+        }
+    }
+}
+
+async fn run_session(
+    state: SharedAppState,
+    mut session: Session,
+    mut messages: AggregatedMessageStream,
+    mut updates: Receiver<Arc<TrainUpdate>>,
+) {
+    let mut subscriptions = Subscriptions::default();
+    let mut ping = tokio::time::interval(PING_INTERVAL);
+    let mut last_heard = tokio::time::Instant::now();
+
+    let reason = loop {
+        let sent = tokio::select! {
+            message = messages.next() => {
+                last_heard = tokio::time::Instant::now();
+                match message {
+                    Some(Ok(AggregatedMessage::Text(text))) => {
+                        handle_client_message(&state, &mut session, &mut subscriptions, &text).await
+                    }
+                    Some(Ok(AggregatedMessage::Ping(bytes))) => session.pong(&bytes).await,
+                    Some(Ok(AggregatedMessage::Close(reason))) => break reason,
+                    Some(Ok(_)) => Ok(()),
+                    Some(Err(e)) => {
+                        warn!("Websocket protocol error: {e}");
+                        break None;
+                    }
+                    None => break None,
+                }
+            }
+            update = updates.recv() => match update {
+                Ok(update) if subscriptions.matches(&update.train) => {
+                    send(
+                        &mut session,
+                        &ServerMessage::Update {
+                            train: &update.train,
+                            changes: &update.changes,
+                        },
+                    )
+                    .await
+                }
+                Ok(_) => Ok(()),
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Websocket subscriber lagged, skipped {skipped} train updates.");
+                    Ok(())
+                }
+                Err(RecvError::Closed) => break None,
+            },
+            _ = ping.tick() => {
+                if last_heard.elapsed() > CLIENT_TIMEOUT {
+                    info!("Closing unresponsive websocket.");
+                    break None;
+                }
+                session.ping(b"").await
+            }
+        };
+        if sent.is_err() {
+            // The client already closed the connection.
+            return;
+        }
+    };
+    let _ = session.close(reason).await;
+}
+
+/// Upgrades to a websocket that pushes updates for the trains and lines the client subscribes
+/// to.
+pub async fn train_updates(
+    req: HttpRequest,
+    body: web::Payload,
+    data: web::Data<SharedAppState>,
+) -> actix_web::Result<HttpResponse> {
+    let (response, session, messages) = actix_ws::handle(&req, body)?;
+    let state = data.get_ref().clone();
+    let updates = state.read().await.train_sender.subscribe();
+    actix_web::rt::spawn(run_session(
+        state,
+        session,
+        messages.aggregate_continuations(),
+        updates,
+    ));
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn train(trainno: &str, line: &str) -> TrainView {
+        serde_json::from_value(serde_json::json!({
+            "trainno": trainno,
+            "service": "LOCAL",
+            "dest": "Thorndale",
+            "currentstop": "Ardmore",
+            "nextstop": "Haverford",
+            "line": line,
+            "consist": "815,816",
+            "late": 0,
+            "SOURCE": "30th Street Station",
+        }))
+        .unwrap()
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn subscriptions_match_by_train_or_line() {
+        let paoli = train("1001", "Paoli/Thorndale");
+        let trenton = train("9212", "Trenton");
+        let mut subscriptions = Subscriptions::default();
+        assert!(!subscriptions.matches(&paoli));
+
+        subscriptions.subscribe(strings(&["1001"]), vec![]);
+        assert!(subscriptions.matches(&paoli));
+        assert!(!subscriptions.matches(&trenton));
+
+        subscriptions.subscribe(vec![], strings(&["Trenton", "Paoli/Thorndale"]));
+        assert!(subscriptions.matches(&trenton));
+
+        // Still subscribed to its line.
+        subscriptions.unsubscribe(&strings(&["1001"]), &[]);
+        assert!(subscriptions.matches(&paoli));
+        subscriptions.unsubscribe(&[], &strings(&["Paoli/Thorndale", "Cynwyd"]));
+        assert!(!subscriptions.matches(&paoli));
+        assert!(subscriptions.matches(&trenton));
+    }
+
+    #[test]
+    fn client_messages() {
+        let message: ClientMessage =
+            serde_json::from_str(r#"{"action": "subscribe", "lines": ["Trenton"]}"#).unwrap();
+        assert!(matches!(
+            message,
+            ClientMessage::Subscribe { trains, lines } if trains.is_empty() && lines == ["Trenton"]
+        ));
+        assert!(serde_json::from_str::<ClientMessage>(r#"{"action": "follow"}"#).is_err());
+    }
+}
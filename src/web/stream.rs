@@ -0,0 +1,285 @@
+use std::{collections::VecDeque, time::Duration};
+
+use actix_web::{
+    HttpRequest, HttpResponse, Responder,
+    web::{self, Bytes},
+};
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt, stream};
+use serde::Deserialize;
+use sqlx::PgPool;
+use tokio::sync::broadcast::{Receiver, error::RecvError};
+use uuid::Uuid;
+
+use crate::{
+    SharedAppState,
+    db::tracking::{ChangeEvent, ChangeFilter},
+};
+
+/// How many persisted changes are read at a time while catching a resumed stream up.
+const BACKLOG_PAGE_SIZE: i64 = 500;
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Deserialize)]
+pub struct StreamChangesQuery {
+    #[serde(flatten)]
+    filter: ChangeFilter,
+    /// For clients that can't set the `Last-Event-ID` header.
+    last_event_id: Option<Uuid>,
+}
+
+/// Keyset changes are published and persisted in. Timestamps are compared at microsecond
+/// precision since that's all postgres keeps.
+type EventKey = (i64, Uuid);
+
+fn event_key(changed_at: DateTime<Utc>, id: Uuid) -> EventKey {
+    (changed_at.timestamp_micros(), id)
+}
+
+enum Phase {
+    /// Replaying persisted changes after the given key.
+    Backlog(DateTime<Utc>, Uuid),
+    Live,
+}
+
+struct ChangeStream {
+    pg_pool: PgPool,
+    receiver: Receiver<ChangeEvent>,
+    filter: ChangeFilter,
+    phase: Phase,
+    pending: VecDeque<ChangeEvent>,
+    /// How far through the persisted changes the stream has got. Anything live up to it is a
+    /// duplicate, and a subscriber that lags goes back to the backlog from here.
+    last_sent: (DateTime<Utc>, Uuid),
+}
+
+impl ChangeStream {
+    async fn next_event(&mut self) -> Option<ChangeEvent> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(event);
+            }
+            match self.phase {
+                Phase::Backlog(changed_at, id) => {
+                    match ChangeEvent::fetch_after(
+                        self.pg_pool.clone(),
+                        changed_at,
+                        id,
+                        &self.filter,
+                        BACKLOG_PAGE_SIZE,
+                    )
+                    .await
+                    {
+                        Ok(page) => {
+                            self.phase = match page.last() {
+                                Some(last) if page.len() as i64 == BACKLOG_PAGE_SIZE => {
+                                    Phase::Backlog(last.change.changed_at, last.change.id)
+                                }
+                                _ => Phase::Live,
+                            };
+                            if let Some(last) = page.last() {
+                                self.last_sent = (last.change.changed_at, last.change.id);
+                            }
+                            self.pending.extend(page);
+                        }
+                        Err(e) => {
+                            // Going live would skip whatever's left of the backlog. Everything
+                            // fetched so far has been sent, so ending the stream lets the client
+                            // reconnect with its Last-Event-ID and resume from there instead.
+                            error!("Error fetching change backlog, ending the stream: {e}");
+                            return None;
+                        }
+                    }
+                }
+                Phase::Live => match self.receiver.recv().await {
+                    Ok(event) => {
+                        let key = event_key(event.change.changed_at, event.change.id);
+                        if key <= event_key(self.last_sent.0, self.last_sent.1) {
+                            continue;
+                        }
+                        self.last_sent = (event.change.changed_at, event.change.id);
+                        if self.filter.matches(&event) {
+                            return Some(event);
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        // Everything skipped was saved before it was published, so it's refilled
+                        // from the database.
+                        warn!(
+                            "Change stream subscriber lagged, catching up {skipped} changes from the backlog."
+                        );
+                        self.phase = Phase::Backlog(self.last_sent.0, self.last_sent.1);
+                    }
+                    Err(RecvError::Closed) => return None,
+                },
+            }
+        }
+    }
+}
+
+fn format_event(event: &ChangeEvent) -> Bytes {
+    let data = serde_json::to_string(event).unwrap_or_default();
+    Bytes::from(format!(
+        "id: {}\nevent: change\ndata: {data}\n\n",
+        event.change.id
+    ))
+}
+
+fn keep_alive() -> impl Stream<Item = Bytes> {
+    stream::unfold(
+        tokio::time::interval_at(
+            tokio::time::Instant::now() + KEEP_ALIVE_INTERVAL,
+            KEEP_ALIVE_INTERVAL,
+        ),
+        |mut interval| async move {
+            interval.tick().await;
+            Some((Bytes::from_static(b": keep-alive\n\n"), interval))
+        },
+    )
+}
+
+/// Where a stream starts: after the `Last-Event-ID` change when resuming, otherwise after the
+/// newest persisted change, so there's somewhere to catch up from if it lags before sending
+/// anything.
+async fn stream_start(
+    pg_pool: PgPool,
+    last_event_id: Option<Uuid>,
+) -> anyhow::Result<(DateTime<Utc>, Uuid)> {
+    if let Some(id) = last_event_id {
+        match ChangeEvent::changed_at_of(pg_pool.clone(), id).await? {
+            Some(changed_at) => return Ok((changed_at, id)),
+            None => warn!("Unknown Last-Event-ID {id}, streaming new changes only."),
+        }
+    }
+    Ok(ChangeEvent::latest_key(pg_pool)
+        .await?
+        .unwrap_or((DateTime::UNIX_EPOCH, Uuid::nil())))
+}
+
+/// Streams changes as they're processed. Resuming with `Last-Event-ID` first replays every
+/// persisted change after that one, and a subscriber that falls behind is caught up the same way.
+pub async fn stream_changes(
+    req: HttpRequest,
+    query: web::Query<StreamChangesQuery>,
+    data: web::Data<SharedAppState>,
+) -> impl Responder {
+    let header_id = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty());
+    let last_event_id = match header_id.map(Uuid::parse_str) {
+        Some(Ok(id)) => Some(id),
+        Some(Err(e)) => {
+            return HttpResponse::BadRequest().body(format!("Invalid Last-Event-ID: {e}"));
+        }
+        None => query.last_event_id,
+    };
+
+    let pg_pool = data.read().await.pg_pool.clone();
+    let (changed_at, id) = match stream_start(pg_pool.clone(), last_event_id).await {
+        Ok(start) => start,
+        Err(e) => {
+            error!("Error finding where to start the change stream: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    // Subscribed after finding the start, and caught up from it, so nothing is missed in between.
+    let receiver = data.read().await.change_sender.subscribe();
+
+    let changes = stream::unfold(
+        ChangeStream {
+            pg_pool,
+            receiver,
+            filter: query.into_inner().filter,
+            phase: Phase::Backlog(changed_at, id),
+            pending: VecDeque::new(),
+            last_sent: (changed_at, id),
+        },
+        |mut changes| async move {
+            let event = changes.next_event().await?;
+            Some((format_event(&event), changes))
+        },
+    );
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(stream::select(changes, keep_alive()).map(Ok::<_, actix_web::Error>))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tracking::{Changed, Value};
+    use tokio::sync::broadcast;
+
+    fn change(secs: i64) -> ChangeEvent {
+        ChangeEvent {
+            change: Changed {
+                id: Uuid::new_v4(),
+                trainno: "1001".to_string(),
+                record_id: Uuid::new_v4(),
+                changed_at: DateTime::from_timestamp(1_792_200_000 + secs, 0).unwrap(),
+                field: "late".to_string(),
+                old_value: Value::Int(0),
+                new_value: Value::Int(1),
+                _type: "Integer".to_string(),
+            },
+            line: String::new(),
+        }
+    }
+
+    #[sqlx::test]
+    async fn lagging_subscriber_catches_up_from_the_backlog(pg_pool: PgPool) {
+        let (sender, receiver) = broadcast::channel(2);
+        let mut stream = ChangeStream {
+            pg_pool: pg_pool.clone(),
+            receiver,
+            filter: ChangeFilter::default(),
+            phase: Phase::Live,
+            pending: VecDeque::new(),
+            last_sent: (DateTime::UNIX_EPOCH, Uuid::nil()),
+        };
+
+        // Saved, then published, like processing does, overflowing the channel.
+        let events: Vec<ChangeEvent> = (0..5).map(change).collect();
+        let mut tx = pg_pool.begin().await.unwrap();
+        let changes: Vec<Changed> = events.iter().map(|event| event.change.clone()).collect();
+        Changed::commit_changes(&changes, &mut tx).await.unwrap();
+        tx.commit().await.unwrap();
+        for event in &events {
+            sender.send(event.clone()).unwrap();
+        }
+        let late = change(5);
+        sender.send(late.clone()).unwrap();
+
+        let mut received = vec![];
+        for _ in 0..6 {
+            received.push(stream.next_event().await.unwrap().change.id);
+        }
+        let expected: Vec<Uuid> = events
+            .iter()
+            .chain([&late])
+            .map(|event| event.change.id)
+            .collect();
+        assert_eq!(received, expected);
+    }
+
+    #[sqlx::test]
+    async fn failed_backlog_fetch_ends_the_stream(pg_pool: PgPool) {
+        let (sender, receiver) = broadcast::channel(2);
+        let mut stream = ChangeStream {
+            pg_pool: pg_pool.clone(),
+            receiver,
+            filter: ChangeFilter::default(),
+            phase: Phase::Backlog(DateTime::UNIX_EPOCH, Uuid::nil()),
+            pending: VecDeque::new(),
+            last_sent: (DateTime::UNIX_EPOCH, Uuid::nil()),
+        };
+        pg_pool.close().await;
+        sender.send(change(0)).unwrap();
+
+        assert!(stream.next_event().await.is_none());
+    }
+}